@@ -1,14 +1,33 @@
-use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
-
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
 use std::collections::{HashMap, HashSet, LinkedList};
 use std::fs;
-use std::path::Path;
-
-use crate::config::Plugin;
-use crate::lock::get_lock_entry;
-use crate::{env, merger::merge_yamls};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, Core, FileRule, Handler, Plugin, ProxyRole};
+use crate::download::unpack_tarball;
+use crate::env;
+use crate::hash::hash_bytes;
+use crate::lock::{
+    core_key, datapack_key, get_lock_entry, plugin_key, resource_pack_key, world_template_key,
+};
+use crate::merger::{merge_layers, Format};
+use crate::project::Project;
+use crate::properties::Properties;
+use crate::proxy::{render_proxy, secret_path};
+use crate::server::{render_resource_pack, render_server, EULA_URL};
+
+// Relative to project root
+pub const DEFAULT_TARGET_DIR: &str = "build";
+
+// Fingerprint of last build, stored in target
+pub const FINGERPRINT_FILE: &str = ".mcstarter-build";
+
+// Gitignore-like list of files which are not copied to server
+const IGNORE_FILE: &str = ".mcstarterignore";
 
 pub async fn build_plugins(
     plugins: &HashMap<String, Plugin>,
@@ -23,7 +42,7 @@ pub async fn build_plugins(
     for (name, plugin) in plugins {
         let name_version = format!("{name}-{}", plugin.version);
 
-        let hash = get_lock_entry(name, &lock)?;
+        let hash = get_lock_entry(&plugin_key(name, plugin), lock)?;
 
         let plugin_filename = format!("{name_version}-{hash}.jar");
         let target_path_str = format!("{target}/plugins/{plugin_filename}");
@@ -41,25 +60,113 @@ pub async fn build_plugins(
     for file_in_plugins_dir in files_in_plugins_dir {
         let file_in_target_dir = file_in_plugins_dir?;
         let file_name = file_in_target_dir.file_name();
-        match file_name.to_str() {
-            Some(name) => {
-                if name.ends_with(".jar") && !plugin_files.contains(name) {
-                    fs::remove_file(file_in_target_dir.path())?;
+        if let Some(name) = file_name.to_str() {
+            if name.ends_with(".jar") && !plugin_files.contains(name) {
+                fs::remove_file(file_in_target_dir.path())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// World directory from built server.properties, so level-name of includes counts too
+fn world_dir(target: &str) -> Result<String> {
+    let path = Path::new(target).join("server.properties");
+    let level_name = if path.exists() {
+        Properties::parse(&fs::read_to_string(path)?).get("level-name")
+    } else {
+        None
+    };
+    let level_name = level_name.filter(|name| !name.is_empty());
+    Ok(format!(
+        "{target}/{}",
+        level_name.as_deref().unwrap_or("world")
+    ))
+}
+
+// Unpack world template unless world already exists, so rebuild doesn't reset it
+pub fn build_world(
+    config: &Config,
+    lock: &HashMap<String, String>,
+    target: &str,
+    cache: &String,
+) -> Result<()> {
+    let template = match &config.world_template {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+    let world_dir = world_dir(target)?;
+    if Path::new(&world_dir).exists() {
+        return Ok(());
+    }
+
+    let hash = get_lock_entry(&world_template_key(&template.url), lock)?;
+    let bytes = fs::read(format!("{cache}/{hash}"))
+        .context("world template is not downloaded, run mcstarter download")?;
+    println!("Unpacking world template into {world_dir}");
+    unpack_tarball(&bytes, Path::new(&world_dir))
+}
+
+// Goes after server files and world, level-name is taken from built server.properties
+pub async fn build_datapacks(
+    config: &Config,
+    lock: &HashMap<String, String>,
+    target: &str,
+    cache: &String,
+) -> Result<()> {
+    let datapacks_dir = format!("{}/datapacks", world_dir(target)?);
+    let mut datapack_files: HashSet<String> = HashSet::new();
+
+    for (name, datapack) in &config.datapacks {
+        let hash = get_lock_entry(&datapack_key(name, datapack), lock)?;
+
+        let datapack_filename = format!("{name}-{}-{hash}.zip", datapack.version);
+        let target_path_str = format!("{datapacks_dir}/{datapack_filename}");
+        let target_path = Path::new(&target_path_str);
+
+        datapack_files.insert(datapack_filename);
+
+        if !target_path.exists() {
+            fs::create_dir_all(&datapacks_dir)?;
+            fs::copy(format!("{cache}/{hash}"), target_path)?;
+        }
+    }
+
+    // Remove old datapacks, but not ones added to world by hand
+    if Path::new(&datapacks_dir).exists() {
+        for file in fs::read_dir(&datapacks_dir)? {
+            let file = file?;
+            let file_name = file.file_name();
+            if let Some(name) = file_name.to_str() {
+                if is_locked_file(name, ".zip") && !datapack_files.contains(name) {
+                    fs::remove_file(file.path())?;
                 }
             }
-            None => {}
         }
     }
 
     Ok(())
 }
 
+// Whether file name ends with `-<sha256>` and extension, like built by mcstarter
+fn is_locked_file(name: &str, extension: &str) -> bool {
+    match name
+        .strip_suffix(extension)
+        .and_then(|n| n.rsplit_once('-'))
+    {
+        Some((_, hash)) => hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
 pub async fn build_core(
+    core: &Core,
     lock: &HashMap<String, String>,
     target: &String,
     cache: &String,
 ) -> Result<()> {
-    let hash = get_lock_entry(&String::from("core"), &lock)?;
+    let hash = get_lock_entry(&core_key(core), lock)?;
 
     let core_filename = format!("core-{hash}.jar");
     let target_path_str = format!("{target}/{core_filename}");
@@ -74,192 +181,494 @@ pub async fn build_core(
     for file_in_target_dir in files_in_target_dir {
         let file_in_target_dir = file_in_target_dir?;
         let file_name = file_in_target_dir.file_name();
-        match file_name.to_str() {
-            Some(name) => {
-                if name.ends_with(".jar") && name.starts_with("core-") && name != core_filename {
-                    fs::remove_file(file_in_target_dir.path())?;
-                }
+        if let Some(name) = file_name.to_str() {
+            if name.ends_with(".jar") && name.starts_with("core-") && name != core_filename {
+                fs::remove_file(file_in_target_dir.path())?;
             }
-            None => {}
         }
     }
 
     Ok(())
 }
 
-pub async fn build_files(includes: &LinkedList<String>, target: &String) -> Result<()> {
-    let mut yml_configs: HashMap<String, Yaml> = HashMap::new();
-    let mut etc_configs: HashMap<String, String> = HashMap::new();
-    let mut etc_files: HashMap<String, String> = HashMap::new();
+pub async fn build_files(
+    project: &Project,
+    config: &Config,
+    vars: &env::Vars,
+    lock: &HashMap<String, String>,
+    target: &String,
+    cache: &String,
+) -> Result<()> {
+    let rendered = render_files(project, config, vars, lock, target, cache)?;
+
+    // Proxy doesn't need EULA
+    let is_proxy = config
+        .proxy
+        .as_ref()
+        .is_some_and(|proxy| proxy.role == ProxyRole::Proxy);
+    if config.server.eula {
+        println!("Accepting Minecraft EULA ({EULA_URL}) as set in server.eula");
+    } else if !is_proxy
+        && !rendered.contains("eula.txt")
+        && !Path::new(target).join("eula.txt").exists()
+    {
+        println!("Server won't start until EULA ({EULA_URL}) is accepted, set server.eula to true");
+    }
 
-    let mut ignore_dirs: HashSet<String> = HashSet::new();
+    for (key, data) in &rendered.files {
+        let path_str = format!("{target}/{key}");
+        let path = Path::new(&path_str);
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+    }
 
-    ignore_dirs.insert(target.clone());
+    for (key, value) in &rendered.copies {
+        let out_path_str = format!("{target}/{key}");
+        let out_path = Path::new(&out_path_str);
+        let in_path = Path::new(value);
+
+        fs::create_dir_all(out_path.parent().unwrap())?;
+
+        fs::copy(in_path, out_path)?;
+    }
+    Ok(())
+}
+
+// Server files of build, relative to target
+pub struct Rendered {
+    // Configs with substituted variables
+    pub files: Vec<(String, String)>,
+    // Files copied as is, with their source
+    pub copies: Vec<(String, String)>,
+}
+
+impl Rendered {
+    pub fn contains(&self, key: &str) -> bool {
+        self.files.iter().chain(&self.copies).any(|(k, _)| k == key)
+    }
+
+    // Remove file, so it can be replaced with changed data
+    pub fn take(&mut self, key: &str) -> Result<Option<String>> {
+        if let Some(i) = self.files.iter().position(|(k, _)| k == key) {
+            return Ok(Some(self.files.remove(i).1));
+        }
+        if let Some(i) = self.copies.iter().position(|(k, _)| k == key) {
+            let (_, source) = self.copies.remove(i);
+            return Ok(Some(fs::read_to_string(source)?));
+        }
+        Ok(None)
+    }
 
-    for include in includes {
+    // Merge data over file in given format
+    pub fn merge(&mut self, key: &str, format: Format, data: String) -> Result<()> {
+        let mut layers: Vec<String> = self.take(key)?.into_iter().collect();
+        layers.push(data);
+        let merged = merge_layers(format, &layers).with_context(|| format!("can't merge {key}"))?;
+        self.put(key, merged);
+        Ok(())
+    }
+
+    pub fn put(&mut self, key: &str, data: String) {
+        self.files.retain(|(k, _)| k != key);
+        self.copies.retain(|(k, _)| k != key);
+        self.files.push((key.to_string(), data));
+    }
+}
+
+// Source files of server from includes and project, not rendered yet
+fn scan_files(
+    project: &Project,
+    config: &Config,
+    target: &String,
+    cache: &String,
+) -> Result<Files> {
+    let mut files = Files::default();
+    let rules = compile_rules(&config.files)?;
+
+    // Includes are scanned only as separate layers, never as subdirs of other layers
+    let mut ignore_paths: HashSet<PathBuf> = HashSet::new();
+
+    if Path::new(target).exists() {
+        ignore_paths.insert(fs::canonicalize(target)?);
+    }
+    ignore_paths.insert(fs::canonicalize(&project.config)?);
+    if project.lock.exists() {
+        ignore_paths.insert(fs::canonicalize(&project.lock)?);
+    }
+    for include in &config.include_dirs {
+        ignore_paths.insert(fs::canonicalize(include)?);
+    }
+
+    // Includes of other profiles
+    for profile in config.profiles.values() {
+        if let Some(includes) = profile.get("include").and_then(|i| i.as_sequence()) {
+            ignore_paths.extend(
+                includes
+                    .iter()
+                    .filter_map(|i| i.as_str())
+                    .filter_map(|i| fs::canonicalize(project.root.join(i)).ok()),
+            );
+        }
+    }
+
+    // Default cache is skipped even if other one is used
+    for cache in [cache, &project.cache] {
+        if Path::new(cache).exists() {
+            ignore_paths.insert(fs::canonicalize(cache)?);
+        }
+    }
+    if let Some(proxy) = &config.proxy {
+        let secret_file = secret_path(project, proxy);
+        if secret_file.exists() {
+            ignore_paths.insert(fs::canonicalize(secret_file)?);
+        }
+    }
+
+    for include in &config.include_dirs {
         scan_dir(
             Path::new(include),
-            &mut yml_configs,
-            &mut etc_configs,
-            &mut etc_files,
+            &mut files,
             include,
-            &ignore_dirs,
+            &ignore_paths,
+            &layer_excludes(include, &config.exclude)?,
+            &rules,
         )?;
-        ignore_dirs.insert(include.clone());
     }
 
-
+    let root = project.root.display().to_string();
     scan_dir(
-        Path::new("./"),
-        &mut yml_configs,
-        &mut etc_configs,
-        &mut etc_files,
-        &String::from("./"),
-        &ignore_dirs,
+        &project.root,
+        &mut files,
+        &root,
+        &ignore_paths,
+        &layer_excludes(&root, &config.exclude)?,
+        &rules,
     )?;
+    Ok(files)
+}
 
-    for (key, value) in yml_configs {
-        let path_str = format!("{target}/{key}");
-        let path = Path::new(&path_str);
+// Render server files in memory, without touching target
+fn render_files(
+    project: &Project,
+    config: &Config,
+    vars: &env::Vars,
+    lock: &HashMap<String, String>,
+    target: &String,
+    cache: &String,
+) -> Result<Rendered> {
+    let context = env::Context::new(serde_yaml::to_value(config)?, vars);
+    let files = scan_files(project, config, target, cache)?;
+
+    // Rendered configs, written only if all variables are substituted
+    let mut rendered: Vec<(String, String)> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+
+    // Layers with sources, other formats are substituted before merge
+    let mut configs: Vec<(String, Format, Layers)> = Vec::new();
+    for (key, (format, value)) in files.configs {
+        let mut layers: Vec<String> = Vec::new();
+        let mut sources: Vec<(String, String)> = Vec::new();
+        for layer_path in value {
+            let data = fs::read_to_string(&layer_path)?;
+            if format == Format::Yaml {
+                layers.push(data.clone());
+            } else {
+                let (data, failures) = env::substitute(&data, &context);
+                errors.extend(failures.iter().map(|f| f.locate(&layer_path, &[])));
+                layers.push(data);
+            }
+            sources.push((layer_path, data));
+        }
+        configs.push((key, format, (layers, sources)));
+    }
 
-        let mut out_str = String::new();
-        let mut emitter = YamlEmitter::new(&mut out_str);
-        emitter.dump(&value)?;
+    for (key, value) in files.etc_configs {
+        let data = fs::read_to_string(&value)?;
 
-        fs::create_dir_all(path.parent().unwrap())?;
-        fs::write(path, env::pass_envs(&out_str)?)?;
+        let (data, failures) = env::substitute(&data, &context);
+        errors.extend(failures.iter().map(|f| f.locate(&value, &[])));
+
+        rendered.push((key, data));
     }
 
-    for (key, value) in etc_configs {
-        let out_path_str = format!("{target}/{key}");
-        let out_path = Path::new(&out_path_str);
-        let in_path = Path::new(&value);
+    // Missing variable would break parsing of JSON or TOML, so it's reported first
+    env::check_errors(&errors)?;
 
-        let data = fs::read_to_string(&in_path)?;
+    // YAML is substituted after merge, so overridden placeholders don't fail
+    for (key, format, (layers, sources)) in configs {
+        let path_str = format!("{target}/{key}");
 
-        fs::create_dir_all(out_path.parent().unwrap())?;
-        fs::write(out_path, env::pass_envs(&data)?)?;
+        let mut out_str =
+            merge_layers(format, &layers).with_context(|| format!("can't merge {key}"))?;
+        if format == Format::Yaml {
+            let (data, failures) = env::substitute(&out_str, &context);
+            errors.extend(failures.iter().map(|f| f.locate(&path_str, &sources)));
+            out_str = data;
+        }
+
+        rendered.push((key, out_str));
     }
 
-    for (key, value) in etc_files {
-        let out_path_str = format!("{target}/{key}");
-        let out_path = Path::new(&out_path_str);
-        let in_path = Path::new(&value);
+    env::check_errors(&errors)?;
 
-        fs::create_dir_all(out_path.parent().unwrap())?;
+    let mut rendered = Rendered {
+        files: rendered,
+        copies: files.etc_files.into_iter().collect(),
+    };
+    render_server(&config.server, &mut rendered)?;
+    if let Some(resource_pack) = &config.resource_pack {
+        render_resource_pack(resource_pack, lock, cache, &mut rendered)?;
+    }
+    if let Some(proxy) = &config.proxy {
+        render_proxy(project, proxy, &config.core, &mut rendered)?;
+    }
+    Ok(rendered)
+}
+
+// Hash of build inputs: configs, used lock entries and source files.
+// Sources reference variables only by name, so values of variables
+// (or absence of build-time ones at launch) don't change it.
+pub fn fingerprint(
+    project: &Project,
+    config: &Config,
+    profile: Option<&str>,
+    lock: &HashMap<String, String>,
+    target: &String,
+    cache: &String,
+) -> Result<String> {
+    let mut entries: Vec<String> = Vec::new();
+
+    entries.push(format!("profile {}", profile.unwrap_or_default()));
+    let mut configs = vec![project.config.clone()];
+    configs.extend(
+        config
+            .include_dirs
+            .iter()
+            .map(|dir| Path::new(dir).join("mcstarter.yml")),
+    );
+    for (i, path) in configs.iter().enumerate() {
+        if path.exists() {
+            entries.push(format!("config {i} {}", hash_file(path)?));
+        }
+    }
+
+    entries.push(format!(
+        "core {}",
+        get_lock_entry(&core_key(&config.core), lock)?
+    ));
+    for (name, plugin) in &config.plugins {
+        let hash = get_lock_entry(&plugin_key(name, plugin), lock)?;
+        entries.push(format!("plugin {name}-{}-{hash}.jar", plugin.version));
+    }
+    for (name, datapack) in &config.datapacks {
+        let hash = get_lock_entry(&datapack_key(name, datapack), lock)?;
+        entries.push(format!("datapack {name}-{}-{hash}.zip", datapack.version));
+    }
+    if let Some(resource_pack) = &config.resource_pack {
+        let hash = get_lock_entry(&resource_pack_key(&resource_pack.url), lock)?;
+        entries.push(format!("resource_pack {hash}"));
+    }
+    if let Some(template) = &config.world_template {
+        let hash = get_lock_entry(&world_template_key(&template.url), lock)?;
+        entries.push(format!("world_template {hash}"));
+    }
 
-        fs::copy(&in_path, &out_path)?;
+    let files = scan_files(project, config, target, cache)?;
+    for (key, (_, layers)) in &files.configs {
+        for (i, layer) in layers.iter().enumerate() {
+            entries.push(format!("config {key} {i} {}", hash_file(Path::new(layer))?));
+        }
+    }
+    for (key, value) in files.etc_configs.iter().chain(&files.etc_files) {
+        entries.push(format!("file {key} {}", hash_file(Path::new(value))?));
     }
+
+    // Files are collected from hash maps, so their order is random
+    entries.sort();
+    Ok(hash_bytes(&Bytes::from(entries.join("\n"))))
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    Ok(hash_bytes(&Bytes::from(fs::read(path)?)))
+}
+
+pub fn save_fingerprint(target: &str, fingerprint: &str) -> Result<()> {
+    fs::write(format!("{target}/{FINGERPRINT_FILE}"), fingerprint)?;
     Ok(())
 }
 
+// Whether target was built from current config, lock and files,
+// None if it has no fingerprint, e.g. it was built by older mcstarter
+pub fn is_up_to_date(
+    project: &Project,
+    config: &Config,
+    profile: Option<&str>,
+    lock: &HashMap<String, String>,
+    target: &String,
+    cache: &String,
+) -> Result<Option<bool>> {
+    let saved = match fs::read_to_string(format!("{target}/{FINGERPRINT_FILE}")) {
+        Ok(saved) => saved,
+        Err(_) => return Ok(None),
+    };
+    let current = fingerprint(project, config, profile, lock, target, cache)?;
+    Ok(Some(saved.trim() == current))
+}
+
 fn scan_dir(
     dir: &Path,
-    yml_configs: &mut HashMap<String, Yaml>,
-    etc_configs: &mut HashMap<String, String>,
-    etc_files: &mut HashMap<String, String>,
+    files: &mut Files,
     strip_prefix: &String,
-    ignore_dirs: &HashSet<String>,
+    ignore_paths: &HashSet<PathBuf>,
+    excludes: &Gitignore,
+    rules: &[(GlobMatcher, &FileRule)],
 ) -> Result<()> {
-    if ignore_dirs.contains(&String::from(dir.to_str().unwrap())) {
-        return Ok(());
-    }
-
-    let files = fs::read_dir(dir)?;
-    for file in files {
+    for file in fs::read_dir(dir)? {
         let file = file?;
         let path = file.path();
 
         let file_name = path.file_name().unwrap();
-        if file_name == "mcstarter.yml" || file_name == "mcstarter.lock" || file_name == ".git" {
+        if file_name == "mcstarter.yml"
+            || file_name == "mcstarter.lock"
+            || file_name == ".git"
+            || file_name == IGNORE_FILE
+        {
+            continue;
+        }
+
+        if excludes.matched(&path, path.is_dir()).is_ignore()
+            || fs::canonicalize(&path).is_ok_and(|p| ignore_paths.contains(&p))
+        {
+            continue;
+        }
+
+        // Don't leak variables from .env files into server
+        let file_name_str = file_name.to_string_lossy();
+        if file_name_str == ".env" || file_name_str.starts_with(".env.") {
             continue;
         }
 
         if path.is_file() {
-            let extension = path.extension();
             let file_path_stripped =
                 String::from(path.strip_prefix(strip_prefix)?.to_str().unwrap());
-            match extension {
-                Some(ext) => {
-                    if ext == "yml" || ext == "yaml" {
-                        handle_yml_config(yml_configs, &file_path_stripped, &path)?;
-                    } else if ext == "properties"
-                        || ext == "conf"
-                        || ext == "txt"
-                        || ext == "json"
-                        || ext == "toml"
-                    {
-                        handle_etc_config(etc_configs, &file_path_stripped, &path)?;
-                    } else {
-                        handle_etc_file(etc_files, &file_path_stripped, &path)?;
-                    }
-                }
-                None => {
-                    handle_etc_file(etc_files, &file_path_stripped, &path)?;
-                }
+            let file_path = String::from(path.to_str().unwrap());
+            match classify(&file_path_stripped, rules)? {
+                Kind::Merge(format) => files.add_config(file_path_stripped, format, file_path),
+                Kind::Template => files.add_etc_config(file_path_stripped, file_path),
+                Kind::Copy => files.add_etc_file(file_path_stripped, file_path),
+                Kind::Skip => {}
             }
         } else if path.is_dir() {
-            scan_dir(
-                &path,
-                yml_configs,
-                etc_configs,
-                etc_files,
-                strip_prefix,
-                &ignore_dirs,
-            )?;
+            scan_dir(&path, files, strip_prefix, ignore_paths, excludes, rules)?;
         }
     }
 
     Ok(())
 }
 
-fn handle_etc_config(
-    etc_configs: &mut HashMap<String, String>,
-    name: &String,
-    file: &Path,
-) -> Result<()> {
-    etc_configs.insert(name.clone(), String::from(file.to_str().unwrap()));
-    Ok(())
+// Kind of file with resolved format
+enum Kind {
+    Merge(Format),
+    Template,
+    Copy,
+    Skip,
 }
 
-fn handle_etc_file(
-    etc_files: &mut HashMap<String, String>,
-    name: &String,
-    file: &Path,
-) -> Result<()> {
-    etc_files.insert(name.clone(), String::from(file.to_str().unwrap()));
-    Ok(())
+// Handler from first matching rule, otherwise by extension
+fn classify(name: &str, rules: &[(GlobMatcher, &FileRule)]) -> Result<Kind> {
+    let rule = rules.iter().find(|(glob, _)| glob.is_match(name));
+    let format = rule
+        .and_then(|(_, rule)| rule.format)
+        .or_else(|| Format::from_path(Path::new(name)));
+
+    let kind = match rule.map(|(_, rule)| rule.handler) {
+        Some(Handler::Merge) => match format {
+            Some(format) => Kind::Merge(format),
+            None => bail!("can't detect format of {name}, set it in files rule"),
+        },
+        Some(Handler::Template) => Kind::Template,
+        Some(Handler::Copy) => Kind::Copy,
+        Some(Handler::Skip) => Kind::Skip,
+        None => match format {
+            Some(format) => Kind::Merge(format),
+            None if name.ends_with(".txt") => Kind::Template,
+            None => Kind::Copy,
+        },
+    };
+    Ok(kind)
 }
 
-fn handle_yml_config(
-    yml_configs: &mut HashMap<String, Yaml>,
-    name: &String,
-    path: &Path,
-) -> Result<()> {
-    let data = fs::read_to_string(&path)?;
-    let parsed = YamlLoader::load_from_str(&data)?;
+fn compile_rules(rules: &LinkedList<FileRule>) -> Result<Vec<(GlobMatcher, &FileRule)>> {
+    rules
+        .iter()
+        .map(|rule| {
+            // Like in .gitignore, glob without `/` matches at any depth
+            let glob = if rule.glob.contains('/') {
+                rule.glob.trim_start_matches('/').to_string()
+            } else {
+                format!("**/{}", rule.glob)
+            };
+            let matcher = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("invalid glob {}", rule.glob))?
+                .compile_matcher();
+            Ok((matcher, rule))
+        })
+        .collect()
+}
+
+// Patterns from layer's .mcstarterignore and `exclude` in mcstarter.yml
+fn layer_excludes(dir: &str, exclude: &LinkedList<String>) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
 
-    if parsed.len() == 0 {
-        if !yml_configs.contains_key(name) {
-            yml_configs.insert(name.clone(), Yaml::Null);
+    let ignore_file = Path::new(dir).join(IGNORE_FILE);
+    if ignore_file.exists() {
+        if let Some(err) = builder.add(&ignore_file) {
+            return Err(err).with_context(|| format!("can't parse {}", ignore_file.display()));
         }
-        Ok(())
-    } else if parsed.len() > 1 {
-        Err(anyhow!("yaml {} has more than 1 docs", name))
-    } else {
-        let parsed = &parsed[0];
+    }
+    for pattern in exclude {
+        builder.add_line(None, pattern)?;
+    }
+
+    Ok(builder.build()?)
+}
 
-        let current_config = yml_configs.get(name);
+// Layers to merge and their unsubstituted sources with paths
+type Layers = (Vec<String>, Vec<(String, String)>);
 
-        match current_config {
-            Some(current_config) => {
-                let new_config = merge_yamls(current_config, parsed);
-                yml_configs.insert(name.clone(), new_config);
-            }
-            None => {
-                yml_configs.insert(name.clone(), parsed.clone());
-            }
+// Files of all layers, each name has single kind taken from highest layer
+#[derive(Default)]
+struct Files {
+    // Configs are merged later, in order of includes
+    configs: HashMap<String, (Format, Vec<String>)>,
+    etc_configs: HashMap<String, String>,
+    etc_files: HashMap<String, String>,
+}
+
+impl Files {
+    fn add_config(&mut self, name: String, format: Format, file: String) {
+        self.etc_configs.remove(&name);
+        self.etc_files.remove(&name);
+        let (config_format, layers) = self.configs.entry(name).or_insert((format, Vec::new()));
+        if *config_format != format {
+            *config_format = format;
+            layers.clear();
         }
-        Ok(())
+        layers.push(file);
+    }
+
+    fn add_etc_config(&mut self, name: String, file: String) {
+        self.configs.remove(&name);
+        self.etc_files.remove(&name);
+        self.etc_configs.insert(name, file);
+    }
+
+    fn add_etc_file(&mut self, name: String, file: String) {
+        self.configs.remove(&name);
+        self.etc_configs.remove(&name);
+        self.etc_files.insert(name, file);
     }
 }
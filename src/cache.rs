@@ -1,12 +1,18 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-use crate::config::{Config, Core, Plugin};
-use crate::download::{download_hashed_core, download_hashed_plugin};
-use crate::lock::get_lock_entry;
+use crate::config::{Config, Core, Datapack, Plugin, ResourcePack, WorldTemplate};
+use crate::download::{download_hashed_core, download_hashed_plugin, download_url};
+use crate::hash::hash_bytes;
+use crate::lock::{
+    core_key, datapack_key, get_lock_entry, plugin_key, resource_pack_key, world_template_key,
+};
+
+// Relative to project root
+pub const DEFAULT_CACHE_DIR: &str = "cache";
 
 pub async fn cache_core(
     core: &Core,
@@ -14,14 +20,14 @@ pub async fn cache_core(
     lock: &HashMap<String, String>,
     cache_dir: &String,
 ) -> Result<()> {
-    let hash = get_lock_entry(&String::from("core"), &lock)?;
+    let hash = get_lock_entry(&core_key(core), lock)?;
 
     let path_str = format!("{cache_dir}/{hash}");
     let path = Path::new(&path_str);
 
     if !path.exists() {
         let core_bytes = download_hashed_core(core, config, &hash).await?;
-        fs::write(&path, core_bytes)?;
+        fs::write(path, core_bytes)?;
     }
     Ok(())
 }
@@ -38,6 +44,68 @@ pub async fn cache_plugins(
     Ok(())
 }
 
+pub async fn cache_datapacks(
+    datapacks: &HashMap<String, Datapack>,
+    lock: &HashMap<String, String>,
+    cache_dir: &String,
+) -> Result<()> {
+    for (name, datapack) in datapacks {
+        let hash = get_lock_entry(&datapack_key(name, datapack), lock)?;
+
+        let path_str = format!("{cache_dir}/{hash}");
+        let path = Path::new(&path_str);
+
+        if !path.exists() {
+            let datapack_bytes = download_url(&datapack.url).await?;
+            if hash_bytes(&datapack_bytes) != hash {
+                bail!("datapack {name} has invalid hash");
+            }
+            fs::write(path, datapack_bytes)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn cache_resource_pack(
+    resource_pack: &ResourcePack,
+    lock: &HashMap<String, String>,
+    cache_dir: &String,
+) -> Result<()> {
+    let hash = get_lock_entry(&resource_pack_key(&resource_pack.url), lock)?;
+
+    let path_str = format!("{cache_dir}/{hash}");
+    let path = Path::new(&path_str);
+
+    if !path.exists() {
+        let pack_bytes = download_url(&resource_pack.url).await?;
+        if hash_bytes(&pack_bytes) != hash {
+            bail!("resource pack has invalid hash");
+        }
+        fs::write(path, pack_bytes)?;
+    }
+    Ok(())
+}
+
+pub async fn cache_world_template(
+    template: &WorldTemplate,
+    lock: &HashMap<String, String>,
+    cache_dir: &String,
+) -> Result<()> {
+    let hash = get_lock_entry(&world_template_key(&template.url), lock)?;
+
+    let path_str = format!("{cache_dir}/{hash}");
+    let path = Path::new(&path_str);
+
+    if !path.exists() {
+        let template_bytes = download_url(&template.url).await?;
+        if hash_bytes(&template_bytes) != hash {
+            bail!("world template has invalid hash");
+        }
+        fs::write(path, template_bytes)?;
+    }
+    Ok(())
+}
+
 async fn cache_plugin(
     name: &String,
     plugin: &Plugin,
@@ -45,7 +113,7 @@ async fn cache_plugin(
     lock: &HashMap<String, String>,
     cache_dir: &String,
 ) -> Result<()> {
-    let hash = get_lock_entry(name, &lock)?;
+    let hash = get_lock_entry(&plugin_key(name, plugin), lock)?;
 
     let path_str = format!("{cache_dir}/{hash}");
     let path = Path::new(&path_str);
@@ -1,19 +1,33 @@
-use anyhow::{bail, Result};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
-use std::path::Path;
 
+use yaml_rust::yaml::Hash;
 use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
 
-use std::collections::{HashMap, LinkedList};
+use std::collections::{BTreeMap, HashMap, LinkedList};
 
+use crate::include::{Include, IncludesConfig, Resolver};
+use crate::merger::Format;
+use crate::project::Project;
 use crate::{env, merger};
 
-fn default_includes() -> LinkedList<String> {
-    let includes: LinkedList<String> = LinkedList::new();
+fn default_includes() -> LinkedList<Include> {
+    let includes: LinkedList<Include> = LinkedList::new();
     includes
 }
 
+fn default_excludes() -> LinkedList<String> {
+    let excludes: LinkedList<String> = LinkedList::new();
+    excludes
+}
+
+fn default_file_rules() -> LinkedList<FileRule> {
+    let rules: LinkedList<FileRule> = LinkedList::new();
+    rules
+}
+
 fn default_sources() -> HashMap<String, Source> {
     let sources: HashMap<String, Source> = HashMap::new();
     sources
@@ -24,10 +38,39 @@ fn default_plugins() -> HashMap<String, Plugin> {
     plugins
 }
 
+fn default_datapacks() -> HashMap<String, Datapack> {
+    let datapacks: HashMap<String, Datapack> = HashMap::new();
+    datapacks
+}
+
+fn default_schedule() -> LinkedList<ScheduledTask> {
+    let schedule: LinkedList<ScheduledTask> = LinkedList::new();
+    schedule
+}
+
+fn default_profiles() -> HashMap<String, serde_yaml::Value> {
+    let profiles: HashMap<String, serde_yaml::Value> = HashMap::new();
+    profiles
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Config {
+    // Server name, `${config.name}` in configs
+    pub name: Option<String>,
     #[serde(default = "default_includes")]
-    pub include: LinkedList<String>,
+    pub include: LinkedList<Include>,
+    // Gitignore patterns of files which are not copied to server
+    #[serde(default = "default_excludes")]
+    pub exclude: LinkedList<String>,
+    // How files are built, first matching rule wins
+    #[serde(default = "default_file_rules")]
+    pub files: LinkedList<FileRule>,
+    // Resolved include directories, in merge order
+    #[serde(skip)]
+    pub include_dirs: Vec<String>,
+    // Lock entries of remote includes
+    #[serde(skip)]
+    pub remote_includes: HashMap<String, String>,
     #[serde(default = "default_sources")]
     pub sources: HashMap<String, Source>,
     pub default_source: Option<String>,
@@ -35,6 +78,24 @@ pub struct Config {
     pub core: Core,
     #[serde(default = "default_plugins")]
     pub plugins: HashMap<String, Plugin>,
+    // Locked like plugins, put into datapacks of world
+    #[serde(default = "default_datapacks")]
+    pub datapacks: HashMap<String, Datapack>,
+    // Unpacked into world directory if it doesn't exist
+    pub world_template: Option<WorldTemplate>,
+    // Server resource pack, its sha1 is set in server.properties
+    pub resource_pack: Option<ResourcePack>,
+    // Tasks run by supervisor
+    #[serde(default = "default_schedule")]
+    pub schedule: LinkedList<ScheduledTask>,
+    // eula.txt and server.properties
+    #[serde(default = "default_server")]
+    pub server: Server,
+    // Velocity forwarding between proxy and backends
+    pub proxy: Option<Proxy>,
+    // Overrides merged over config when profile is selected
+    #[serde(default = "default_profiles")]
+    pub profiles: HashMap<String, serde_yaml::Value>,
 }
 
 impl Config {
@@ -61,12 +122,410 @@ fn default_mc_args() -> LinkedList<String> {
     mc_args
 }
 
+fn default_supervisor() -> Supervisor {
+    Supervisor {
+        enabled: default_supervisor_enabled(),
+        backoff: default_backoff(),
+        max_backoff: default_max_backoff(),
+        max_restarts: default_max_restarts(),
+        window: default_window(),
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Launch {
     #[serde(default = "default_java_args")]
     pub java_args: LinkedList<String>,
     #[serde(default = "default_mc_args")]
     pub mc_args: LinkedList<String>,
+    #[serde(default = "default_supervisor")]
+    pub supervisor: Supervisor,
+    #[serde(default = "default_shutdown")]
+    pub shutdown: Shutdown,
+    #[serde(default = "default_java")]
+    pub java: Java,
+    // Heap size, e.g. `4G` or `75%` of container memory limit
+    pub memory: Option<String>,
+    // JVM flags added before java_args
+    #[serde(default = "default_preset")]
+    pub preset: Preset,
+    // Environment variables of server
+    #[serde(default = "default_launch_env")]
+    pub env: HashMap<String, String>,
+}
+
+fn default_launch_env() -> HashMap<String, String> {
+    let env: HashMap<String, String> = HashMap::new();
+    env
+}
+
+fn default_preset() -> Preset {
+    Preset::None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    // Aikar's G1 flags
+    Aikar,
+    Zgc,
+    None,
+}
+
+fn default_shutdown() -> Shutdown {
+    Shutdown {
+        countdown: default_countdown(),
+        message: default_countdown_message(),
+        commands: default_shutdown_commands(),
+        stop: default_stop_command(),
+        timeout: default_shutdown_timeout(),
+    }
+}
+
+// Java used to run server, found automatically if path is not set
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Java {
+    pub path: Option<String>,
+    // Minimal major version, by Minecraft version if not set
+    pub version: Option<u32>,
+    // JDK downloaded into cache, used instead of installed one
+    pub download: Option<JavaDownload>,
+}
+
+fn default_adoptium_api() -> String {
+    String::from("https://api.adoptium.net")
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct JavaDownload {
+    // .tar.gz with JDK
+    pub url: Option<String>,
+    // Adoptium release, e.g. `jdk-21.0.4+7`
+    pub release: Option<String>,
+    // Adoptium-compatible API
+    #[serde(default = "default_adoptium_api")]
+    pub api: String,
+    // Platforms of release which are locked besides current one, e.g. `linux-x64`
+    #[serde(default = "default_java_platforms")]
+    pub platforms: Vec<String>,
+}
+
+fn default_java_platforms() -> Vec<String> {
+    vec![String::from("linux-x64"), String::from("linux-aarch64")]
+}
+
+fn default_supervisor_enabled() -> bool {
+    false
+}
+
+fn default_java() -> Java {
+    Java {
+        path: None,
+        version: None,
+        download: None,
+    }
+}
+
+fn default_backoff() -> u64 {
+    5
+}
+
+fn default_max_backoff() -> u64 {
+    300
+}
+
+fn default_max_restarts() -> usize {
+    5
+}
+
+fn default_window() -> u64 {
+    600
+}
+
+// Keep mcstarter as parent of java, restarting it on crashes
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Supervisor {
+    #[serde(default = "default_supervisor_enabled")]
+    pub enabled: bool,
+    // Delay before first restart in seconds, doubled after every crash
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    // Give up after more crashes than this in `window` seconds
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: usize,
+    #[serde(default = "default_window")]
+    pub window: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileRule {
+    // Glob relative to layer, without `/` matches file name at any depth
+    pub glob: String,
+    pub handler: Handler,
+    // Format of merged file, by extension if not set
+    pub format: Option<Format>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Handler {
+    // Merge layers, then substitute variables
+    Merge,
+    // Substitute variables, highest layer wins
+    Template,
+    // Copy as is, highest layer wins
+    Copy,
+    // Don't build file from this layer
+    Skip,
+}
+
+fn default_countdown() -> Vec<u64> {
+    let countdown: Vec<u64> = Vec::new();
+    countdown
+}
+
+fn default_countdown_message() -> String {
+    String::from("say Server is stopping in {seconds} seconds")
+}
+
+fn default_shutdown_commands() -> Vec<String> {
+    vec![String::from("save-all")]
+}
+
+fn default_stop_command() -> String {
+    String::from("stop")
+}
+
+fn default_shutdown_timeout() -> u64 {
+    60
+}
+
+// How supervisor stops server on SIGTERM and SIGINT
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Shutdown {
+    // Seconds before stop when message is sent, e.g. [60, 30, 10]
+    #[serde(default = "default_countdown")]
+    pub countdown: Vec<u64>,
+    // Console command, `{seconds}` is replaced with seconds left
+    #[serde(default = "default_countdown_message")]
+    pub message: String,
+    // Console commands sent before stop
+    #[serde(default = "default_shutdown_commands")]
+    pub commands: Vec<String>,
+    #[serde(default = "default_stop_command")]
+    pub stop: String,
+    // Seconds to wait for server to stop before killing it
+    #[serde(default = "default_shutdown_timeout")]
+    pub timeout: u64,
+}
+
+fn default_scheduled_restart() -> bool {
+    false
+}
+
+// Restart or console command at times matching cron expression
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledTask {
+    pub cron: String,
+    // Restart with shutdown sequence
+    #[serde(default = "default_scheduled_restart")]
+    pub restart: bool,
+    pub command: Option<String>,
+}
+
+fn default_server() -> Server {
+    Server {
+        eula: default_eula(),
+        properties: default_server_properties(),
+    }
+}
+
+fn default_eula() -> bool {
+    false
+}
+
+fn default_server_properties() -> ServerProperties {
+    ServerProperties::default()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Server {
+    // Acceptance of https://aka.ms/MinecraftEULA, written into eula.txt
+    #[serde(default = "default_eula")]
+    pub eula: bool,
+    // Set over server.properties from includes
+    #[serde(default = "default_server_properties")]
+    pub properties: ServerProperties,
+}
+
+// server.properties keys, known ones are checked, others are set as is
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerProperties {
+    #[serde(default, deserialize_with = "lenient")]
+    pub server_port: Option<u16>,
+    pub server_ip: Option<String>,
+    pub motd: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub online_mode: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub max_players: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub view_distance: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub simulation_distance: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub difficulty: Option<Difficulty>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub gamemode: Option<GameMode>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub hardcore: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub pvp: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub allow_flight: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub white_list: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub enforce_whitelist: Option<bool>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub spawn_protection: Option<u32>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub enable_command_block: Option<bool>,
+    pub level_name: Option<String>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_yaml::Value>,
+}
+
+// Typed value, which is a string if it was templated, e.g. `"${PORT}"`.
+// Placeholder which isn't substituted (config loaded without env) is left unset.
+fn lenient<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = match serde_yaml::Value::deserialize(deserializer)? {
+        serde_yaml::Value::String(value) if value.contains("${") => return Ok(None),
+        serde_yaml::Value::String(value) => {
+            serde_yaml::from_str(&value).map_err(serde::de::Error::custom)?
+        }
+        value => value,
+    };
+    Option::<T>::deserialize(value).map_err(serde::de::Error::custom)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Peaceful,
+    Easy,
+    Normal,
+    Hard,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    Survival,
+    Creative,
+    Adventure,
+    Spectator,
+}
+
+fn default_proxy_online_mode() -> bool {
+    true
+}
+
+fn default_proxy_software() -> ProxySoftware {
+    ProxySoftware::Velocity
+}
+
+// Velocity modern forwarding, backends share secret with proxy,
+// or BungeeCord IP forwarding without secret
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Proxy {
+    pub role: ProxyRole,
+    #[serde(default = "default_proxy_software")]
+    pub software: ProxySoftware,
+    // Online mode of proxy, backends are always offline
+    #[serde(default = "default_proxy_online_mode")]
+    pub online_mode: bool,
+    // Forwarding secret, read from secret_file if not set
+    pub secret: Option<String>,
+    // Relative to config, generated if missing [default: forwarding.secret next to workspace]
+    pub secret_file: Option<String>,
+    // Backends added to velocity.toml or config.yml of proxy
+    #[serde(default = "default_proxy_servers")]
+    pub servers: Vec<ProxyServer>,
+    // Servers tried on join, in order [default: all servers]
+    #[serde(rename = "try")]
+    pub try_servers: Option<Vec<String>>,
+}
+
+fn default_proxy_servers() -> Vec<ProxyServer> {
+    let servers: Vec<ProxyServer> = Vec::new();
+    servers
+}
+
+fn default_server_host() -> String {
+    String::from("127.0.0.1")
+}
+
+fn default_forced_hosts() -> Vec<String> {
+    let forced_hosts: Vec<String> = Vec::new();
+    forced_hosts
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProxyServer {
+    pub name: String,
+    #[serde(default = "default_server_host")]
+    pub host: String,
+    pub port: u16,
+    // Hostnames which connect players straight to this server
+    #[serde(default = "default_forced_hosts")]
+    pub forced_hosts: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyRole {
+    // Velocity or BungeeCord
+    Proxy,
+    // Paper behind proxy
+    Backend,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxySoftware {
+    Velocity,
+    // BungeeCord and its forks like Waterfall
+    Bungeecord,
+}
+
+// Datapacks have no sources, so URL is required
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Datapack {
+    pub version: String,
+    pub url: String,
+}
+
+// .tar.gz with world, e.g. prepared map of lobby
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldTemplate {
+    pub url: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResourcePack {
+    pub url: String,
+    // Message shown to players, `resource-pack-prompt`
+    pub prompt: Option<String>,
+    // Kick players who decline it, `require-resource-pack`
+    pub required: Option<bool>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -87,47 +546,168 @@ pub struct Plugin {
     pub version: String,
     pub source: Option<String>,
     pub url: Option<String>,
+    // Workspace member may use other version than the rest
+    #[serde(default, rename = "override")]
+    pub version_override: bool,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct IncludesConfig {
-    pub include: Option<LinkedList<String>>,
-}
-
-pub fn load_config(pass_env: bool) -> Result<Config> {
-    let main_config_file = fs::read_to_string("./mcstarter.yml")?;
+pub async fn load_config(
+    project: &Project,
+    pass_env: bool,
+    vars: &env::Vars,
+    profile: Option<&str>,
+    cache: &str,
+) -> Result<Config> {
+    let main_config_path = project.config.display().to_string();
+    let main_config_file = fs::read_to_string(&project.config)
+        .with_context(|| format!("can't read {main_config_path}"))?;
     let includes_config: IncludesConfig = serde_yaml::from_str(&main_config_file)?;
 
     let mut parsed_main_cfg_list = YamlLoader::load_from_str(&main_config_file)?;
     let parsed_main_cfg = parsed_main_cfg_list.pop().unwrap();
 
-    let cfg = match includes_config.include {
-        Some(includes) => {
-            let mut yaml_config = Yaml::Null;
+    let includes: Vec<Include> = includes_config
+        .include
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let mut resolver = Resolver::new(cache);
+    resolver
+        .resolve_all(&project.shared_includes, &project.shared_dir)
+        .await?;
+    resolver.resolve_all(&includes, &project.root).await?;
+    let mut cfg = merge_includes(&resolver.configs, &parsed_main_cfg)?;
 
-            for include in includes {
-                let config_path = Path::new(&include).join("mcstarter.yml");
-                if config_path.exists() {
-                    let config_to_merge = fs::read_to_string(&config_path)?;
-                    let parsed_config_to_merge = &YamlLoader::load_from_str(&config_to_merge)?[0];
-                    yaml_config = merger::merge_yamls(&yaml_config, parsed_config_to_merge);
-                }
-            }
+    if let Some(profile) = profile {
+        let profile_cfg = match &cfg["profiles"][profile] {
+            Yaml::BadValue => bail!("profile {profile} not found"),
+            Yaml::Null => Yaml::Hash(Hash::new()),
+            profile_cfg => profile_cfg.clone(),
+        };
 
-            merger::merge_yamls(&yaml_config, &parsed_main_cfg)
+        // Profile includes go after common ones, but still below mcstarter.yml
+        let profile_includes = parse_includes(&profile_cfg["include"])?;
+        if !profile_includes.is_empty() {
+            resolver
+                .resolve_all(&profile_includes, &project.root)
+                .await?;
+            cfg = merge_includes(&resolver.configs, &parsed_main_cfg)?;
         }
-        None => parsed_main_cfg,
+
+        cfg = merger::merge_yamls(&cfg, &profile_cfg)?;
+    }
+
+    // Profiles aren't substituted, so variables of other profiles don't need to be set
+    let profiles = match &mut cfg {
+        Yaml::Hash(hash) => hash.remove(&Yaml::String(String::from("profiles"))),
+        _ => None,
     };
 
+    // Config files in merge order, for error messages
+    let mut sources = resolver.configs.clone();
+    sources.push((main_config_path.clone(), main_config_file));
+
     let mut config_str = String::new();
     let mut emitter = YamlEmitter::new(&mut config_str);
     emitter.dump(&cfg)?;
 
-    let config: Config = if pass_env {
-        serde_yaml::from_str(&env::pass_envs(&config_str)?)?
+    let mut config: Config = if pass_env {
+        // References see substituted values, e.g. `${config.name}` when name is `${NAME}`
+        let raw_context = env::Context::new(serde_yaml::from_str(&config_str)?, vars);
+        let (substituted, _) = env::substitute(&config_str, &raw_context);
+        let context = env::Context::new(serde_yaml::from_str(&substituted)?, vars);
+        let (config_str, failures) = env::substitute(&config_str, &context);
+        let errors: Vec<String> = failures
+            .iter()
+            .map(|f| f.locate(&main_config_path, &sources))
+            .collect();
+        env::check_errors(&errors)?;
+        serde_yaml::from_str(&config_str)?
     } else {
         serde_yaml::from_str(&config_str)?
     };
 
+    if let Some(profiles @ Yaml::Hash(_)) = profiles {
+        let mut profiles_str = String::new();
+        YamlEmitter::new(&mut profiles_str).dump(&profiles)?;
+        config.profiles = serde_yaml::from_str(&profiles_str)?;
+    }
+    config.include_dirs = resolver.dirs;
+    config.remote_includes = resolver.remotes;
+
     Ok(config)
 }
+
+// Merge mcstarter.yml of includes and main config
+fn merge_includes(configs: &[(String, String)], main_cfg: &Yaml) -> Result<Yaml> {
+    let mut yaml_config = Yaml::Null;
+
+    for (config_path, config_to_merge) in configs {
+        let mut parsed_config_to_merge = YamlLoader::load_from_str(config_to_merge)?
+            .pop()
+            .unwrap_or(Yaml::Null);
+
+        // Nested includes are already resolved relative to their config
+        if let Yaml::Hash(hash) = &mut parsed_config_to_merge {
+            hash.remove(&Yaml::String(String::from("include")));
+        }
+
+        yaml_config = merger::merge_yamls(&yaml_config, &parsed_config_to_merge)
+            .with_context(|| format!("can't merge {config_path}"))?;
+    }
+
+    merger::merge_yamls(&yaml_config, main_cfg)
+}
+
+fn parse_includes(includes: &Yaml) -> Result<Vec<Include>> {
+    match includes {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        includes => {
+            let mut includes_str = String::new();
+            YamlEmitter::new(&mut includes_str).dump(includes)?;
+            Ok(serde_yaml::from_str(&includes_str)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+
+    #[tokio::test]
+    async fn other_profiles_are_not_substituted() {
+        let dir = temp_dir().join(format!("mcstarter-profiles-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("mcstarter.yml"),
+            r#"core: { name: paper, version: "1.19" }
+launch: {}
+profiles:
+  dev:
+    name: dev
+  prod:
+    name: ${MCS_TEST_PROD_ONLY}
+"#,
+        )
+        .unwrap();
+        let project = Project::new(dir.to_str(), None, None);
+        let vars = env::Vars::load(&project.root, Some("dev")).unwrap();
+
+        let config = load_config(&project, true, &vars, Some("dev"), &project.cache)
+            .await
+            .unwrap();
+        assert_eq!(config.name.as_deref(), Some("dev"));
+        assert_eq!(
+            config.profiles["prod"]["name"].as_str(),
+            Some("${MCS_TEST_PROD_ONLY}")
+        );
+
+        let err = load_config(&project, true, &vars, Some("prod"), &project.cache)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("can't substitute variables"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
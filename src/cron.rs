@@ -0,0 +1,231 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+
+// Cron expression: minute, hour, day of month, month and day of week
+pub struct Schedule {
+    // Bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Like in cron, if both days and weekdays are restricted, either matches
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn parse(expr: &str) -> Result<Schedule> {
+        let expr = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            bail!("cron expression {expr} must have 5 fields");
+        };
+
+        let mut weekdays_mask = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays_mask & (1 << 7) != 0 {
+            weekdays_mask |= 1;
+        }
+
+        let days_mask = parse_field(days, 1, 31)?;
+
+        Ok(Schedule {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: days_mask,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_mask,
+            // Field is unrestricted if it allows every value, e.g. `*` or `1-31`
+            any_day: days_mask == full_mask(1, 31),
+            any_weekday: weekdays_mask & full_mask(0, 6) == full_mask(0, 6),
+        })
+    }
+
+    // First matching minute after time, None if there's no such in 5 years
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = time + Duration::days(5 * 366);
+
+        while time < limit {
+            if !has(self.months, time.month()) {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !has(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn full_mask(min: u32, max: u32) -> u64 {
+    (min..=max).fold(0, |mask, value| mask | 1 << value)
+}
+
+fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+// Field of comma-separated `*`, `N` or `N-M`, each with optional `/STEP`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_number(step)?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("invalid cron field {field}");
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_number(start)?, parse_number(end)?)
+        } else {
+            let start = parse_number(range)?;
+            // `N/STEP` means from N to max
+            (start, if part.contains('/') { max } else { start })
+        };
+        if start < min || end > max || start > end {
+            bail!("cron field {field} is out of range {min}-{max}");
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_number(number: &str) -> Result<u32> {
+    number
+        .parse()
+        .map_err(|_| anyhow!("invalid number {number} in cron expression"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(expr: &str, after: &str) -> NaiveDateTime {
+        Schedule::parse(expr)
+            .unwrap()
+            .next_after(time(after))
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(parse_field("*", 0, 5).unwrap(), 0b111111);
+        assert_eq!(parse_field("1,3", 0, 5).unwrap(), 0b1010);
+        assert_eq!(parse_field("1-3", 0, 5).unwrap(), 0b1110);
+        assert_eq!(parse_field("*/2", 0, 5).unwrap(), 0b10101);
+        assert_eq!(parse_field("1/2", 0, 5).unwrap(), 0b101010);
+        assert_eq!(parse_field("0-4/2,5", 0, 5).unwrap(), 0b110101);
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert!(parse_field("6", 0, 5).is_err());
+        assert!(parse_field("3-1", 0, 5).is_err());
+        assert!(parse_field("*/0", 0, 5).is_err());
+        assert!(parse_field("a", 0, 5).is_err());
+        assert!(Schedule::parse("* * * *").is_err());
+    }
+
+    #[test]
+    fn finds_next_time() {
+        assert_eq!(
+            next("30 4 * * *", "2022-07-01 04:30"),
+            time("2022-07-02 04:30")
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2022-07-01 04:31"),
+            time("2022-07-01 04:45")
+        );
+        assert_eq!(
+            next("@monthly", "2022-12-15 10:00"),
+            time("2023-01-01 00:00")
+        );
+        // 2022-07-01 is Friday, both 0 and 7 are Sunday
+        assert_eq!(
+            next("0 0 * * 7", "2022-07-01 00:00"),
+            time("2022-07-03 00:00")
+        );
+        assert_eq!(
+            next("0 0 * * 0", "2022-07-01 00:00"),
+            time("2022-07-03 00:00")
+        );
+    }
+
+    #[test]
+    fn matches_either_restricted_day() {
+        // 13th or Friday
+        assert_eq!(
+            next("0 0 13 * 5", "2022-07-01 00:00"),
+            time("2022-07-08 00:00")
+        );
+        assert_eq!(
+            next("0 0 13 * 5", "2022-07-08 00:00"),
+            time("2022-07-13 00:00")
+        );
+    }
+
+    #[test]
+    fn full_range_is_unrestricted() {
+        // Days and weekdays covering everything don't widen other field
+        assert_eq!(
+            next("0 0 */1 * 1", "2022-07-01 00:00"),
+            time("2022-07-04 00:00")
+        );
+        assert_eq!(
+            next("0 0 1-31 * 1", "2022-07-01 00:00"),
+            time("2022-07-04 00:00")
+        );
+        assert_eq!(
+            next("0 0 13 * 0-6", "2022-07-01 00:00"),
+            time("2022-07-13 00:00")
+        );
+        assert_eq!(
+            next("0 0 13 * */1", "2022-07-01 00:00"),
+            time("2022-07-13 00:00")
+        );
+        // `*/2` is restricted, so either field matches
+        assert_eq!(
+            next("0 0 */2 * 1", "2022-07-01 00:00"),
+            time("2022-07-03 00:00")
+        );
+    }
+}
@@ -3,15 +3,43 @@ extern crate reqwest;
 use anyhow::{anyhow, bail, Result};
 
 use bytes::Bytes;
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crate::config::{Config, Core, Plugin};
 use crate::hash::hash_bytes;
 
-async fn download_url(url: &String) -> Result<Bytes> {
-    let resp = reqwest::get(url).await?.bytes().await?;
+pub async fn download_url(url: &str) -> Result<Bytes> {
+    let resp = reqwest::get(url).await?.error_for_status()?.bytes().await?;
     Ok(resp)
 }
 
+// Unpack .tar.gz into dir, which appears only when it's complete
+pub fn unpack_tarball(bytes: &[u8], dir: &Path) -> Result<()> {
+    let tmp_dir = PathBuf::from(format!("{}.tmp", dir.display()));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir_all(&tmp_dir)?;
+    Archive::new(GzDecoder::new(bytes)).unpack(&tmp_dir)?;
+
+    // Archives of git hostings and JDKs wrap everything into single directory
+    let entries: Vec<PathBuf> = fs::read_dir(&tmp_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    match entries.as_slice() {
+        [single] if single.is_dir() => {
+            fs::rename(single, dir)?;
+            fs::remove_dir(&tmp_dir)?;
+        }
+        _ => fs::rename(&tmp_dir, dir)?,
+    }
+    Ok(())
+}
+
 pub async fn download_core(core: &Core, config: &Config) -> Result<Bytes> {
     // URL specified?
     let url = match &core.url {
@@ -37,14 +65,14 @@ pub async fn download_core(core: &Core, config: &Config) -> Result<Bytes> {
     Ok(core_bytes)
 }
 
-fn prepare_url(url: &String, name: &String, version: &String) -> String {
-    let mut new_url = url.clone();
+fn prepare_url(url: &str, name: &str, version: &str) -> String {
+    let mut new_url = url.to_string();
     new_url = new_url.replace("$NAME", name);
     new_url = new_url.replace("$VERSION", version);
     new_url
 }
 
-pub async fn download_plugin(name: &String, plugin: &Plugin, config: &Config) -> Result<Bytes> {
+pub async fn download_plugin(name: &str, plugin: &Plugin, config: &Config) -> Result<Bytes> {
     // URL specified?
     let url = match &plugin.url {
         // Yes, using it
@@ -61,7 +89,7 @@ pub async fn download_plugin(name: &String, plugin: &Plugin, config: &Config) ->
             // No, using default source
             None => {
                 let source = config.get_default_source()?;
-                prepare_url(&source.url, &name, &plugin.version)
+                prepare_url(&source.url, name, &plugin.version)
             }
         },
     };
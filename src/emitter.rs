@@ -0,0 +1,605 @@
+use anyhow::Result;
+use yaml_rust::yaml::{Hash, Yaml};
+use yaml_rust::{YamlEmitter, YamlLoader};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+// Position of node in YAML document
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Segment {
+    Key(String),
+    // Index of source and index of item in its list, merged lists have items of several sources
+    Item(usize, usize),
+}
+
+// Layout of node taken from source file
+#[derive(Default)]
+struct Layout {
+    // Comment and blank lines before node
+    before: Vec<String>,
+    // Comment on the same line
+    inline: Option<String>,
+    // Scalar as written in source
+    raw: Option<String>,
+    // Quoted key as written in source
+    raw_key: Option<String>,
+    // List items are not indented relative to key
+    compact_seq: bool,
+}
+
+// Comments and styles collected from source files
+#[derive(Default)]
+struct Layouts {
+    header: Vec<String>,
+    footer: Vec<String>,
+    nodes: HashMap<Vec<Segment>, Layout>,
+    // Parsed sources, to find which one list item comes from
+    sources: Vec<Yaml>,
+}
+
+impl Layouts {
+    // Add layout of higher priority source
+    fn overlay(&mut self, other: Layouts) {
+        if !other.header.is_empty() {
+            self.header = other.header;
+        }
+        if !other.footer.is_empty() {
+            self.footer = other.footer;
+        }
+        self.sources.extend(other.sources);
+        for (path, layout) in other.nodes {
+            let current = self.nodes.entry(path).or_default();
+            if !layout.before.is_empty() {
+                current.before = layout.before;
+            }
+            if layout.inline.is_some() {
+                current.inline = layout.inline;
+            }
+            current.raw = layout.raw;
+            if layout.raw_key.is_some() {
+                current.raw_key = layout.raw_key;
+            }
+            current.compact_seq = layout.compact_seq;
+        }
+    }
+
+    // Item of source list which merged list item came from, highest source first
+    fn source_item(
+        &self,
+        path: &[Segment],
+        item: &Yaml,
+        used: &HashSet<Segment>,
+    ) -> Option<Segment> {
+        for (source, document) in self.sources.iter().enumerate().rev() {
+            let Some(Yaml::Array(items)) = node_at(document, source, path) else {
+                continue;
+            };
+            for (index, source_item) in items.iter().enumerate() {
+                let segment = Segment::Item(source, index);
+                if source_item == item && !used.contains(&segment) {
+                    return Some(segment);
+                }
+            }
+        }
+        None
+    }
+}
+
+// Node of source document at path, if path goes through this source
+fn node_at<'a>(document: &'a Yaml, source: usize, path: &[Segment]) -> Option<&'a Yaml> {
+    let mut node = document;
+    for segment in path {
+        node = match (segment, node) {
+            (Segment::Key(key), Yaml::Hash(hash)) => {
+                hash.iter().find(|(k, _)| &key_name(k) == key)?.1
+            }
+            (Segment::Item(item_source, index), Yaml::Array(items)) if *item_source == source => {
+                items.get(*index)?
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+// Emit YAML keeping comments and scalar styles of sources, later sources win
+pub fn emit_yaml(value: &Yaml, sources: &[String]) -> Result<String> {
+    let mut layouts = Layouts::default();
+    for (i, source) in sources.iter().enumerate() {
+        layouts.overlay(scan_layout(source, i));
+    }
+
+    let mut out = String::new();
+    for line in &layouts.header {
+        writeln!(out, "{line}")?;
+    }
+
+    let mut path = Vec::new();
+    match value {
+        Yaml::Hash(hash) if !hash.is_empty() => {
+            emit_hash(&mut out, hash, 0, &mut path, &layouts, None)?
+        }
+        Yaml::Array(vector) if !vector.is_empty() => {
+            emit_array(&mut out, vector, 0, &mut path, &layouts, None)?
+        }
+        Yaml::Null => {}
+        _ => writeln!(out, "{}", scalar(value, None)?)?,
+    }
+
+    for line in &layouts.footer {
+        writeln!(out, "{line}")?;
+    }
+    Ok(out)
+}
+
+fn emit_hash(
+    out: &mut String,
+    hash: &Hash,
+    indent: usize,
+    path: &mut Vec<Segment>,
+    layouts: &Layouts,
+    mut first_prefix: Option<&str>,
+) -> Result<()> {
+    for (key, value) in hash {
+        path.push(Segment::Key(key_name(key)));
+        let layout = layouts.nodes.get(path);
+
+        let prefix = match first_prefix.take() {
+            // First key of list item goes on the same line as dash
+            Some(prefix) => prefix.to_string(),
+            None => {
+                write_before(out, layout, indent)?;
+                " ".repeat(indent)
+            }
+        };
+        let raw_key = layout.and_then(|l| l.raw_key.as_deref());
+        write!(out, "{prefix}{}:", scalar(key, raw_key)?)?;
+        emit_child(out, value, indent, path, layouts, layout)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+fn emit_array(
+    out: &mut String,
+    vector: &[Yaml],
+    indent: usize,
+    path: &mut Vec<Segment>,
+    layouts: &Layouts,
+    mut first_prefix: Option<&str>,
+) -> Result<()> {
+    let mut used: HashSet<Segment> = HashSet::new();
+    for (i, item) in vector.iter().enumerate() {
+        // Item without source, e.g. with merge directives inside, has no layout
+        let segment = layouts
+            .source_item(path, item, &used)
+            .unwrap_or(Segment::Item(usize::MAX, i));
+        used.insert(segment.clone());
+        path.push(segment);
+        let layout = layouts.nodes.get(path);
+
+        let prefix = match first_prefix.take() {
+            // First item of nested list goes on the same line as outer dash
+            Some(prefix) => prefix.to_string(),
+            None => {
+                write_before(out, layout, indent)?;
+                if let Yaml::Hash(hash) = item {
+                    // Comments of first key go before dash
+                    let mut first = path.clone();
+                    if let Some((key, _)) = hash.front() {
+                        first.push(Segment::Key(key_name(key)));
+                    }
+                    write_before(out, layouts.nodes.get(&first), indent)?;
+                }
+                " ".repeat(indent)
+            }
+        };
+        let raw = layout.and_then(|l| l.raw.as_deref());
+
+        match item {
+            Yaml::Hash(hash) if !hash.is_empty() => {
+                let prefix = format!("{prefix}- ");
+                emit_hash(out, hash, indent + 2, path, layouts, Some(&prefix))?;
+            }
+            Yaml::Array(items) if !items.is_empty() && !matches_raw(item, raw) => {
+                let prefix = format!("{prefix}- ");
+                emit_array(out, items, indent + 2, path, layouts, Some(&prefix))?;
+            }
+            _ => {
+                write!(out, "{prefix}-")?;
+                emit_child(out, item, indent, path, layouts, layout)?;
+            }
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+// Emit value after `key:` or `-`
+fn emit_child(
+    out: &mut String,
+    value: &Yaml,
+    indent: usize,
+    path: &mut Vec<Segment>,
+    layouts: &Layouts,
+    layout: Option<&Layout>,
+) -> Result<()> {
+    let inline = match layout.and_then(|l| l.inline.as_ref()) {
+        Some(comment) => format!(" {comment}"),
+        None => String::new(),
+    };
+    let raw = layout.and_then(|l| l.raw.as_deref());
+
+    match value {
+        Yaml::Hash(hash) if !hash.is_empty() && !matches_raw(value, raw) => {
+            writeln!(out, "{inline}")?;
+            emit_hash(out, hash, indent + 2, path, layouts, None)?;
+        }
+        Yaml::Array(vector) if !vector.is_empty() && !matches_raw(value, raw) => {
+            writeln!(out, "{inline}")?;
+            let compact = layout.is_some_and(|l| l.compact_seq);
+            let items_indent = if compact { indent } else { indent + 2 };
+            emit_array(out, vector, items_indent, path, layouts, None)?;
+        }
+        Yaml::String(text) if text.contains('\n') && !matches_raw(value, raw) => {
+            match literal_block(text, indent + 2) {
+                Some((header, body)) => write!(out, " {header}{inline}\n{body}")?,
+                None => writeln!(out, " {}{inline}", scalar(value, raw)?)?,
+            }
+        }
+        _ => writeln!(out, " {}{inline}", scalar(value, raw)?)?,
+    }
+    Ok(())
+}
+
+// Multiline string as `|` block, if it can be represented so
+fn literal_block(text: &str, indent: usize) -> Option<(String, String)> {
+    if text.starts_with([' ', '\n']) || text.contains(['\r', '\t']) {
+        return None;
+    }
+
+    let body = text.trim_end_matches('\n');
+    let chomping = match text.len() - body.len() {
+        0 => "-",
+        1 => "",
+        _ => "+",
+    };
+
+    let mut block = String::new();
+    for line in text.strip_suffix('\n').unwrap_or(text).split('\n') {
+        if line.is_empty() {
+            block.push('\n');
+        } else {
+            block.push_str(&format!("{}{line}\n", " ".repeat(indent)));
+        }
+    }
+    Some((format!("|{chomping}"), block))
+}
+
+fn write_before(out: &mut String, layout: Option<&Layout>, indent: usize) -> Result<()> {
+    if let Some(layout) = layout {
+        for line in &layout.before {
+            if line.is_empty() {
+                writeln!(out)?;
+            } else {
+                writeln!(out, "{}{line}", " ".repeat(indent))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn matches_raw(value: &Yaml, raw: Option<&str>) -> bool {
+    match raw {
+        Some(raw) => match YamlLoader::load_from_str(raw) {
+            Ok(parsed) => parsed.len() == 1 && &parsed[0] == value,
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+// Scalar or flow collection, as in source if value is the same
+fn scalar(value: &Yaml, raw: Option<&str>) -> Result<String> {
+    if let Some(raw) = raw {
+        if matches_raw(value, Some(raw)) {
+            return Ok(raw.to_string());
+        }
+    }
+
+    let mut out = String::new();
+    YamlEmitter::new(&mut out).dump(value)?;
+    Ok(out.trim_start_matches("---").trim_start().to_string())
+}
+
+fn key_name(key: &Yaml) -> String {
+    match key {
+        Yaml::String(s) | Yaml::Real(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        _ => String::from("~"),
+    }
+}
+
+// Collect comments and scalar styles of block YAML document line by line,
+// `index` is position of source in merge order
+fn scan_layout(source: &str, index: usize) -> Layouts {
+    let mut layouts = Layouts::default();
+    let document = YamlLoader::load_from_str(source)
+        .ok()
+        .and_then(|mut docs| docs.pop());
+    layouts.sources.push(document.unwrap_or(Yaml::Null));
+
+    // Open nodes as (indent, segment)
+    let mut stack: Vec<(usize, Segment)> = Vec::new();
+    let mut next_items: HashMap<Vec<Segment>, usize> = HashMap::new();
+    let mut pending: Vec<String> = Vec::new();
+    let mut block_scalar: Option<usize> = None;
+    let mut seen_content = false;
+
+    for line in source.lines() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let content = line.trim();
+
+        if let Some(block_indent) = block_scalar {
+            if content.is_empty() || indent > block_indent {
+                continue;
+            }
+            block_scalar = None;
+        }
+
+        if content.is_empty() {
+            pending.push(String::new());
+            continue;
+        }
+        if content.starts_with('#') {
+            pending.push(content.to_string());
+            continue;
+        }
+        if content == "---" || content.starts_with("%") {
+            continue;
+        }
+        if !seen_content {
+            let start = pending.iter().take_while(|l| l.is_empty()).count();
+            layouts.header = pending.split_off(start);
+            pending.clear();
+            seen_content = true;
+        }
+
+        let mut column = indent;
+        let mut rest = content;
+
+        // List items, possibly with map on the same line
+        while rest == "-" || rest.starts_with("- ") {
+            while let Some((top_indent, top)) = stack.last() {
+                let is_sibling = *top_indent == column && matches!(top, Segment::Item(..));
+                if *top_indent > column || is_sibling {
+                    stack.pop();
+                } else {
+                    break;
+                }
+            }
+            let parent: Vec<Segment> = stack.iter().map(|(_, s)| s.clone()).collect();
+            let item = next_items.entry(parent.clone()).or_insert(0);
+            if *item == 0 {
+                let compact = matches!(stack.last(), Some((i, Segment::Key(_))) if *i == column);
+                layouts.nodes.entry(parent).or_default().compact_seq = compact;
+            }
+            stack.push((column, Segment::Item(index, *item)));
+            *item += 1;
+
+            let after_dash = rest[1..].trim_start();
+            column += rest.len() - after_dash.len();
+            rest = after_dash;
+
+            let path: Vec<Segment> = stack.iter().map(|(_, s)| s.clone()).collect();
+            let layout = layouts.nodes.entry(path).or_default();
+            layout.before = std::mem::take(&mut pending);
+            if rest.is_empty() {
+                break;
+            }
+        }
+
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (key, raw_key, value) = match split_key(rest) {
+            Some((key, raw_key, value)) => {
+                while let Some((top_indent, _)) = stack.last() {
+                    if *top_indent >= column {
+                        stack.pop();
+                    } else {
+                        break;
+                    }
+                }
+                stack.push((column, Segment::Key(key)));
+                (true, raw_key, value)
+            }
+            // Scalar list item
+            None => (false, None, rest),
+        };
+
+        let (value, inline) = split_inline_comment(value);
+        let path: Vec<Segment> = stack.iter().map(|(_, s)| s.clone()).collect();
+        let layout = layouts.nodes.entry(path).or_default();
+        if key {
+            layout.before = std::mem::take(&mut pending);
+            layout.raw_key = raw_key;
+        }
+        layout.inline = inline;
+
+        if value.starts_with('|') || value.starts_with('>') {
+            block_scalar = Some(column);
+        } else if !value.is_empty()
+            && !value.starts_with('&')
+            && !value.starts_with('*')
+            && !value.starts_with('!')
+        {
+            layout.raw = Some(value.to_string());
+        }
+    }
+
+    while pending.last().is_some_and(|l| l.is_empty()) {
+        pending.pop();
+    }
+    layouts.footer = pending;
+    layouts
+}
+
+// Split `key: value` into key, quoted key as written and rest of line
+fn split_key(line: &str) -> Option<(String, Option<String>, &str)> {
+    let (key, raw_key, rest) = if line.starts_with('"') || line.starts_with('\'') {
+        let end = quoted_end(line)?;
+        let key = YamlLoader::load_from_str(&line[..end]).ok()?.pop()?;
+        (
+            key_name(&key),
+            Some(line[..end].to_string()),
+            line[end..].trim_start(),
+        )
+    } else {
+        let mut split = None;
+        for (i, c) in line.char_indices() {
+            if c == ':' && line[i + 1..].chars().next().is_none_or(|n| n == ' ') {
+                split = Some(i);
+                break;
+            }
+            if c == '#' && line[..i].ends_with(' ') {
+                return None;
+            }
+        }
+        let i = split?;
+        (line[..i].trim_end().to_string(), None, &line[i..])
+    };
+
+    let rest = rest.strip_prefix(':')?;
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((key, raw_key, rest.trim_start()))
+}
+
+// Position after closing quote of quoted string at line start
+fn quoted_end(line: &str) -> Option<usize> {
+    let quote = line.chars().next()?;
+    let mut chars = line.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        if quote == '"' && c == '\\' {
+            chars.next();
+        } else if c == quote {
+            if quote == '\'' && line[i + 1..].starts_with('\'') {
+                chars.next();
+                continue;
+            }
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+// Split value and ` # comment` outside of quotes
+fn split_inline_comment(value: &str) -> (&str, Option<String>) {
+    let mut quote: Option<char> = None;
+    let mut chars = value.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some('"') if c == '\\' => {
+                chars.next();
+            }
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => {
+                if (c == '"' || c == '\'') && (i == 0 || value[..i].ends_with([' ', '[', '{', ',']))
+                {
+                    quote = Some(c);
+                } else if c == '#' && (i == 0 || value[..i].ends_with(' ')) {
+                    return (value[..i].trim_end(), Some(value[i..].to_string()));
+                }
+            }
+        }
+    }
+    (value.trim_end(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merger::{merge_layers, Format};
+
+    fn round_trip(source: &str) -> String {
+        let value = YamlLoader::load_from_str(source).unwrap().pop().unwrap();
+        emit_yaml(&value, &[source.to_string()]).unwrap()
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source =
+            "# header\n\n# before key\nkey: value # inline\nhash:\n  # nested\n  a: 1\n# footer\n";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn keeps_scalar_styles() {
+        let source = "single: 'a'\ndouble: \"b\"\nplain: c\nnumber: 010\nbool: yes\nempty: ''\n";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn keeps_quoted_keys() {
+        let source = "\"a.b\": 1\n'x y': 2\n\"1\": three\n";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn keeps_block_scalars() {
+        let source = "motd: |\n  first line\n  second line\n";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn keeps_nested_sequences() {
+        let source = "worlds:\n  - name: world\n    # seed comment\n    seed: 1\n  - name: nether\nmatrix:\n  - - 1\n    - 2\n  - - 3\ncompact:\n- a\n- b\n";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn applies_merge_replace() {
+        let layers = vec![
+            String::from("# worlds\nworlds:\n  - world\n  - nether\nother: 1\n"),
+            String::from("worlds: { __merge: replace, __value: [lobby] }\n"),
+        ];
+        let merged = merge_layers(Format::Yaml, &layers).unwrap();
+        assert_eq!(merged, "# worlds\nworlds:\n  - lobby\nother: 1\n");
+    }
+
+    #[test]
+    fn comments_follow_merged_list_items() {
+        let layers = vec![
+            String::from("worlds:\n  - world\n  - world_nether\n"),
+            String::from("worlds:\n  # lobby comment\n  - lobby\n"),
+        ];
+        let merged = merge_layers(Format::Yaml, &layers).unwrap();
+        assert_eq!(
+            merged,
+            "worlds:\n  - world\n  - world_nether\n  # lobby comment\n  - lobby\n"
+        );
+
+        let layers = vec![
+            String::from("ops:\n  # admin\n  - name: a\n"),
+            String::from("ops: { __merge: prepend, __value: [{ name: b }] }\n"),
+        ];
+        let merged = merge_layers(Format::Yaml, &layers).unwrap();
+        assert_eq!(merged, "ops:\n  - name: b\n  # admin\n  - name: a\n");
+    }
+
+    #[test]
+    fn output_parses_to_same_value() {
+        let source =
+            "a: \"quote \\\" and # hash\"\nb: 'it''s'\nc: \"multi\\nline\"\nd: ~\ne: []\nf: {}\n";
+        let value = YamlLoader::load_from_str(source).unwrap().pop().unwrap();
+        let emitted = round_trip(source);
+        let reparsed = YamlLoader::load_from_str(&emitted).unwrap().pop().unwrap();
+        assert_eq!(reparsed, value);
+    }
+}
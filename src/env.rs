@@ -1,30 +1,431 @@
 use once_cell::sync::Lazy;
-use regex::Regex;
+use regex::{Captures, Regex};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 
+use std::collections::HashMap;
 use std::env::var;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-static ENV_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?:\$\{)([A-Z0-9_]+)(?:\})").unwrap());
-static ENV_REGEX_CLEANER: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Z0-9_]+").unwrap());
+// ${SOURCE[:-default|:?message][ | filter]...} or $${ escape
+static TEMPLATE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?x)
+        (?P<escape>\$\$\{)
+        |
+        \$\{\s*
+        (?P<source>[A-Z0-9_]+|file:[^:|}]+|config(?:\.[A-Za-z0-9_-]+)+)
+        (?:(?P<op>:-|:\?)(?P<arg>[^|}]*))?
+        (?P<filters>(?:\s*\|\s*[a-z0-9]+)*)
+        \s*\}",
+    )
+    .unwrap()
+});
 
-pub fn pass_envs(data: &String) -> Result<String> {
-    let mut new_data = data.clone();
+// Variables from .env files, process environment takes precedence
+#[derive(Clone, Default)]
+pub struct Vars {
+    values: HashMap<String, String>,
+    // Project directory, `file:` paths are relative to it
+    dir: PathBuf,
+    // Files which were checked, for error messages
+    files: Vec<String>,
+}
+
+impl Vars {
+    // Load `.env` and `.env.<profile>`, later file wins
+    pub fn load(dir: &Path, profile: Option<&str>) -> Result<Vars> {
+        let mut vars = Vars {
+            dir: dir.to_path_buf(),
+            ..Vars::default()
+        };
+
+        let mut names = vec![String::from(".env")];
+        if let Some(profile) = profile {
+            names.push(format!(".env.{profile}"));
+        }
+
+        for name in names {
+            let path = dir.join(&name);
+            if path.exists() {
+                let data = fs::read_to_string(&path)?;
+                parse_dotenv(&data, &mut vars.values)
+                    .with_context(|| format!("can't parse {}", path.display()))?;
+            }
+            vars.files.push(path.display().to_string());
+        }
+        Ok(vars)
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match var(name) {
+            Ok(value) => Some(value),
+            Err(_) => self.values.get(name).cloned(),
+        }
+    }
+}
+
+// Values available in templates besides environment
+pub struct Context {
+    config: serde_yaml::Value,
+    vars: Vars,
+}
 
-    for regex_match in ENV_REGEX.find_iter(data) {
-        let regex_match_str = regex_match.as_str();
+impl Context {
+    pub fn new(config: serde_yaml::Value, vars: &Vars) -> Context {
+        Context {
+            config,
+            vars: vars.clone(),
+        }
+    }
 
-        let env_name = ENV_REGEX_CLEANER.find(regex_match_str).unwrap().as_str();
-        let value = var(env_name);
+    // Value of `config.a.b` reference
+    fn config_value(&self, path: &str) -> Result<Option<String>> {
+        let mut value = &self.config;
+        for segment in path.split('.') {
+            let next = match value {
+                serde_yaml::Value::Mapping(mapping) => {
+                    mapping.get(&serde_yaml::Value::String(segment.to_string()))
+                }
+                serde_yaml::Value::Sequence(sequence) => match segment.parse::<usize>() {
+                    Ok(index) => sequence.get(index),
+                    Err(_) => None,
+                },
+                _ => None,
+            };
+            value = match next {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+        }
 
         match value {
-            Ok(value) => {
-                new_data = new_data.replace(regex_match_str, &value);
+            serde_yaml::Value::Null => Ok(None),
+            serde_yaml::Value::Bool(b) => Ok(Some(b.to_string())),
+            serde_yaml::Value::Number(n) => Ok(Some(n.to_string())),
+            serde_yaml::Value::String(s) => Ok(Some(s.clone())),
+            _ => bail!("config.{path} is not a scalar"),
+        }
+    }
+}
+
+// Placeholder which can't be rendered
+pub struct Failure {
+    placeholder: String,
+    line: usize,
+    message: String,
+}
+
+impl Failure {
+    // Describe failure with location in sources (highest priority last),
+    // or in output file if placeholder is not found there
+    pub fn locate(&self, path: &str, sources: &[(String, String)]) -> String {
+        for (source_path, data) in sources.iter().rev() {
+            if let Some(pos) = data.find(&self.placeholder) {
+                let line = line_at(data, pos);
+                return format!("{source_path}:{line}: {}", self.message);
             }
-            Err(_) => {
-                bail!("environment variable {} not found", env_name)
+        }
+        format!("{path}:{}: {}", self.line, self.message)
+    }
+}
+
+// Render all placeholders in one pass, so values are never substituted again
+pub fn substitute(data: &str, context: &Context) -> (String, Vec<Failure>) {
+    let mut new_data = String::with_capacity(data.len());
+    let mut failures: Vec<Failure> = Vec::new();
+    let mut last = 0;
+
+    for captures in TEMPLATE_REGEX.captures_iter(data) {
+        let whole = captures.get(0).unwrap();
+        new_data.push_str(&data[last..whole.start()]);
+        last = whole.end();
+
+        if captures.name("escape").is_some() {
+            new_data.push_str("${");
+            continue;
+        }
+
+        match render(&captures, context) {
+            Ok(value) => new_data.push_str(&value),
+            Err(err) => {
+                new_data.push_str(whole.as_str());
+                failures.push(Failure {
+                    placeholder: whole.as_str().to_string(),
+                    line: line_at(data, whole.start()),
+                    message: err.to_string(),
+                });
             }
         }
     }
-    Ok(new_data)
+    new_data.push_str(&data[last..]);
+
+    (new_data, failures)
+}
+
+// Fail with all collected substitution errors at once
+pub fn check_errors(errors: &[String]) -> Result<()> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        let mut errors = errors.to_vec();
+        errors.sort();
+        errors.dedup();
+        Err(anyhow!(
+            "can't substitute variables:\n{}",
+            errors.join("\n")
+        ))
+    }
+}
+
+fn line_at(data: &str, pos: usize) -> usize {
+    data[..pos].matches('\n').count() + 1
+}
+
+fn render(captures: &Captures, context: &Context) -> Result<String> {
+    let source = &captures["source"];
+
+    let value = if let Some(path) = source.strip_prefix("file:") {
+        let path = path.trim();
+        match fs::read_to_string(context.vars.dir.join(path)) {
+            Ok(data) => Some(data.trim_end_matches(['\n', '\r']).to_string()),
+            Err(err) => bail!("can't read {path}: {err}"),
+        }
+    } else if let Some(path) = source.strip_prefix("config.") {
+        context.config_value(path)?
+    } else {
+        context.vars.get(source)
+    };
+
+    let arg = captures.name("arg").map_or("", |arg| arg.as_str().trim());
+    let mut value = match (value, captures.name("op").map(|op| op.as_str())) {
+        (Some(value), Some(":-")) if value.is_empty() => arg.to_string(),
+        // Like in POSIX shell, empty value fails too
+        (Some(value), Some(":?")) if value.is_empty() => match arg {
+            "" => bail!("{source} is empty"),
+            arg => bail!("{source}: {arg}"),
+        },
+        (Some(value), _) => value,
+        (None, Some(":-")) => arg.to_string(),
+        (None, Some(_)) if !arg.is_empty() => bail!("{source}: {arg}"),
+        (None, _) => match source.strip_prefix("config.") {
+            Some(_) => bail!("config value {source} not found"),
+            None if context.vars.files.is_empty() => {
+                bail!("environment variable {source} not found")
+            }
+            None => bail!(
+                "environment variable {source} not found (also checked {})",
+                context.vars.files.join(", ")
+            ),
+        },
+    };
+
+    for filter in captures["filters"].split('|').skip(1) {
+        value = apply_filter(filter.trim(), &value)?;
+    }
+    Ok(value)
+}
+
+fn apply_filter(filter: &str, value: &str) -> Result<String> {
+    let filtered = match filter {
+        "base64" => base64::encode(value),
+        // Escape for double-quoted JSON and YAML strings
+        "json" => {
+            let quoted = serde_json::to_string(value)?;
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        // Escape for single-quoted YAML strings
+        "yaml" => value.replace('\'', "''"),
+        "trim" => value.trim().to_string(),
+        _ => return Err(anyhow!("unknown filter {filter}")),
+    };
+    Ok(filtered)
+}
+
+// Parse `KEY=value` lines of .env file
+fn parse_dotenv(data: &str, values: &mut HashMap<String, String>) -> Result<()> {
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => bail!("line {}: expected KEY=value", i + 1),
+        };
+
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            match quoted.rfind('"') {
+                Some(end) => unescape(&quoted[..end]),
+                None => bail!("line {}: unterminated quote", i + 1),
+            }
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            match quoted.rfind('\'') {
+                Some(end) => quoted[..end].to_string(),
+                None => bail!("line {}: unterminated quote", i + 1),
+            }
+        } else {
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+
+        values.insert(key.to_string(), value);
+    }
+    Ok(())
+}
+
+// Unescape double-quoted .env value
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(values: &[(&str, &str)]) -> Context {
+        let vars = Vars {
+            values: values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            dir: PathBuf::from("."),
+            files: vec![String::from(".env")],
+        };
+        Context::new(serde_yaml::Value::Null, &vars)
+    }
+
+    fn render_ok(data: &str, context: &Context) -> String {
+        let (rendered, failures) = substitute(data, context);
+        assert!(failures.is_empty(), "{}", failures[0].message);
+        rendered
+    }
+
+    #[test]
+    fn escape_is_kept_literal() {
+        let context = context(&[("MCS_TEST_A", "a")]);
+        assert_eq!(
+            render_ok("$${MCS_TEST_A} ${MCS_TEST_A}", &context),
+            "${MCS_TEST_A} a"
+        );
+    }
+
+    #[test]
+    fn values_are_not_substituted_again() {
+        let context = context(&[("MCS_TEST_A", "${MCS_TEST_B}"), ("MCS_TEST_B", "b")]);
+        assert_eq!(render_ok("x: ${MCS_TEST_A}", &context), "x: ${MCS_TEST_B}");
+    }
+
+    #[test]
+    fn missing_variables_are_reported_together() {
+        let context = context(&[]);
+        let data = "a: ${MCS_TEST_MISSING_A}\nb: 1\nc: ${MCS_TEST_MISSING_B}\n";
+        let (rendered, failures) = substitute(data, &context);
+        assert_eq!(rendered, data);
+
+        let sources = vec![(String::from("mcstarter.yml"), data.to_string())];
+        let errors: Vec<String> = failures
+            .iter()
+            .map(|failure| failure.locate("out.yml", &sources))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "mcstarter.yml:1: environment variable MCS_TEST_MISSING_A not found (also checked .env)",
+                "mcstarter.yml:3: environment variable MCS_TEST_MISSING_B not found (also checked .env)",
+            ]
+        );
+        let message = check_errors(&errors).unwrap_err().to_string();
+        assert!(message.contains("MCS_TEST_MISSING_A") && message.contains("MCS_TEST_MISSING_B"));
+    }
+
+    #[test]
+    fn default_and_required_handle_empty_values() {
+        let context = context(&[("MCS_TEST_EMPTY", ""), ("MCS_TEST_SET", "v")]);
+        assert_eq!(render_ok("${MCS_TEST_EMPTY:-d}", &context), "d");
+        assert_eq!(render_ok("${MCS_TEST_UNSET:- d }", &context), "d");
+        assert_eq!(render_ok("${MCS_TEST_SET:-d}", &context), "v");
+        assert_eq!(render_ok("${MCS_TEST_SET:?needed}", &context), "v");
+
+        let messages = |data: &str| -> Vec<String> {
+            let (_, failures) = substitute(data, &context);
+            failures
+                .into_iter()
+                .map(|failure| failure.message)
+                .collect()
+        };
+        assert_eq!(
+            messages("${MCS_TEST_EMPTY:?}"),
+            vec!["MCS_TEST_EMPTY is empty"]
+        );
+        assert_eq!(
+            messages("${MCS_TEST_EMPTY:?set it}"),
+            vec!["MCS_TEST_EMPTY: set it"]
+        );
+        assert_eq!(
+            messages("${MCS_TEST_UNSET:?set it}"),
+            vec!["MCS_TEST_UNSET: set it"]
+        );
+    }
+
+    #[test]
+    fn filters_are_applied_in_order() {
+        let context = context(&[("MCS_TEST_V", " it's \"q\"\n ")]);
+        assert_eq!(
+            render_ok("${MCS_TEST_V | trim | base64}", &context),
+            base64::encode("it's \"q\"")
+        );
+        assert_eq!(
+            render_ok("${MCS_TEST_V|trim|json}", &context),
+            "it's \\\"q\\\""
+        );
+        assert_eq!(
+            render_ok("${MCS_TEST_V | trim | yaml}", &context),
+            "it''s \"q\""
+        );
+
+        let (_, failures) = substitute("${MCS_TEST_V | upper}", &context);
+        assert_eq!(failures[0].message, "unknown filter upper");
+    }
+
+    #[test]
+    fn parses_dotenv() {
+        let data = "# comment\n\
+            export A=1\n\
+            B = plain value # comment\n\
+            C=\"double \\\"quoted\\\"\\n # not comment\"\n\
+            D='single $x # not comment'\n\
+            E=a#b\n";
+        let mut values = HashMap::new();
+        parse_dotenv(data, &mut values).unwrap();
+        assert_eq!(values["A"], "1");
+        assert_eq!(values["B"], "plain value");
+        assert_eq!(values["C"], "double \"quoted\"\n # not comment");
+        assert_eq!(values["D"], "single $x # not comment");
+        assert_eq!(values["E"], "a#b");
+
+        let err = parse_dotenv("A=1\nB=\"open\n", &mut values).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unterminated quote");
+        let err = parse_dotenv("NO_VALUE\n", &mut values).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: expected KEY=value");
+    }
 }
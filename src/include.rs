@@ -0,0 +1,184 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, LinkedList};
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use tokio::process::Command;
+
+use crate::download::{download_url, unpack_tarball};
+use crate::hash::hash_bytes;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Include {
+    // Local directory, relative to config which includes it
+    Path(String),
+    // Git repository pinned to commit
+    Git { git: String, rev: String },
+    // .tar.gz archive pinned by sha256
+    Tarball { url: String, sha256: String },
+}
+
+impl Include {
+    // Lock entry of remote include, as (key, pin)
+    fn lock_entry(&self) -> Option<(String, String)> {
+        match self {
+            Include::Path(_) => None,
+            Include::Git { git, rev } => Some((format!("include:{git}"), rev.clone())),
+            Include::Tarball { url, sha256 } => Some((format!("include:{url}"), sha256.clone())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IncludesConfig {
+    pub include: Option<LinkedList<Include>>,
+}
+
+// Resolves includes recursively into local directories
+pub struct Resolver {
+    cache: PathBuf,
+    // Includes being resolved, for cycle detection
+    stack: Vec<(PathBuf, String)>,
+    seen: HashSet<PathBuf>,
+    // Include directories in merge order
+    pub dirs: Vec<String>,
+    // mcstarter.yml of includes in merge order, as (path, data)
+    pub configs: Vec<(String, String)>,
+    // Lock entries of remote includes
+    pub remotes: HashMap<String, String>,
+}
+
+impl Resolver {
+    pub fn new(cache: &str) -> Resolver {
+        Resolver {
+            cache: Path::new(cache).join("includes"),
+            stack: Vec::new(),
+            seen: HashSet::new(),
+            dirs: Vec::new(),
+            configs: Vec::new(),
+            remotes: HashMap::new(),
+        }
+    }
+
+    pub async fn resolve_all(&mut self, includes: &[Include], base: &Path) -> Result<()> {
+        for include in includes {
+            self.resolve(include, base).await?;
+        }
+        Ok(())
+    }
+
+    // Resolve include and its includes, which go before it
+    fn resolve<'r>(
+        &'r mut self,
+        include: &'r Include,
+        base: &'r Path,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + 'r>> {
+        Box::pin(async move {
+            let dir = match include {
+                Include::Path(path) => {
+                    if base == Path::new("./") || Path::new(path).is_absolute() {
+                        path.clone()
+                    } else {
+                        base.join(path).display().to_string()
+                    }
+                }
+                Include::Git { git, rev } => self.fetch_git(git, rev).await?,
+                Include::Tarball { url, sha256 } => self.fetch_tarball(url, sha256).await?,
+            };
+            if let Some((key, pin)) = include.lock_entry() {
+                self.remotes.insert(key, pin);
+            }
+
+            let canonical =
+                fs::canonicalize(&dir).with_context(|| format!("include {dir} not found"))?;
+            if self.stack.iter().any(|(path, _)| path == &canonical) {
+                let mut chain: Vec<&str> = self.stack.iter().map(|(_, d)| d.as_str()).collect();
+                chain.push(&dir);
+                bail!("include cycle: {}", chain.join(" -> "));
+            }
+            if self.seen.contains(&canonical) {
+                return Ok(());
+            }
+
+            self.stack.push((canonical.clone(), dir.clone()));
+
+            let config_path = Path::new(&dir).join("mcstarter.yml");
+            if config_path.exists() {
+                let data = fs::read_to_string(&config_path)?;
+                let includes_config: IncludesConfig = serde_yaml::from_str(&data)
+                    .with_context(|| format!("can't parse {}", config_path.display()))?;
+                for child in includes_config.include.unwrap_or_default() {
+                    self.resolve(&child, Path::new(&dir)).await?;
+                }
+                self.configs.push((config_path.display().to_string(), data));
+            }
+
+            self.stack.pop();
+            self.seen.insert(canonical);
+            self.dirs.push(dir);
+            Ok(())
+        })
+    }
+
+    async fn fetch_git(&self, url: &str, rev: &str) -> Result<String> {
+        if rev.len() != 40 || !rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("git include {url} must be pinned to full commit hash");
+        }
+
+        let dir = self.cache.join(format!("git-{rev}"));
+        if !dir.exists() {
+            println!("Fetching {url} at {rev}");
+            let tmp_dir = self.cache.join(format!("git-{rev}.tmp"));
+            if tmp_dir.exists() {
+                fs::remove_dir_all(&tmp_dir)?;
+            }
+            fs::create_dir_all(&self.cache)?;
+
+            run_git(
+                Command::new("git")
+                    .args(["clone", "--quiet", "--no-checkout", url])
+                    .arg(&tmp_dir),
+            )
+            .await?;
+            run_git(
+                Command::new("git")
+                    .arg("-C")
+                    .arg(&tmp_dir)
+                    .args(["checkout", "--quiet", "--detach", rev]),
+            )
+            .await?;
+            fs::rename(&tmp_dir, &dir)?;
+        }
+        Ok(dir.display().to_string())
+    }
+
+    async fn fetch_tarball(&self, url: &str, sha256: &str) -> Result<String> {
+        let dir = self.cache.join(format!("tar-{sha256}"));
+        if !dir.exists() {
+            println!("Fetching {url}");
+            let bytes = download_url(url).await?;
+            if hash_bytes(&bytes) != sha256 {
+                bail!("include {url} has invalid hash");
+            }
+
+            unpack_tarball(&bytes, &dir)?;
+        }
+        Ok(dir.display().to_string())
+    }
+}
+
+async fn run_git(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .await
+        .map_err(|err| anyhow!("can't run git: {err}"))?;
+    if !status.success() {
+        bail!("git failed with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,424 @@
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{Core, Java, JavaDownload};
+use crate::download::{download_url, unpack_tarball};
+use crate::hash::hash_bytes;
+use crate::lock::{get_lock_entry, java_key};
+
+// `1.8.0_292` or `17.0.2` in `java -version` output
+static VERSION_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"version "(?:1\.)?(?P<major>\d+)"#).unwrap());
+
+static MINECRAFT_VERSION_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^1\.(?P<minor>\d+)(?:\.(?P<patch>\d+))?").unwrap());
+
+// Directories where JDKs are usually installed, with `java` in `bin` of their subdirs
+const JDK_DIRS: [&str; 4] = [
+    "/usr/lib/jvm",
+    "/usr/java",
+    "/opt/java",
+    "/Library/Java/JavaVirtualMachines",
+];
+
+// Java executable which satisfies launch.java
+pub fn find_java(
+    java: &Java,
+    core: &Core,
+    lock: &HashMap<String, String>,
+    cache: &str,
+    root: &Path,
+) -> Result<String> {
+    let required = java.version.or_else(|| required_version(&core.version));
+
+    let path = match (&java.path, &java.download) {
+        // Relative path is relative to config, name is searched in PATH
+        (Some(path), _) if path.contains('/') => Some(root.join(path).display().to_string()),
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(download)) => Some(jdk_java(download, lock, cache)?),
+        (None, None) => None,
+    };
+    if let Some(path) = path {
+        return match (java_version(Path::new(&path)), required) {
+            (None, _) => bail!("can't get version of {path}"),
+            (Some(version), Some(required)) if version < required => {
+                bail!("{path} is Java {version}, but {required} or newer is required")
+            }
+            (Some(_), _) => Ok(path),
+        };
+    }
+
+    let mut checked: Vec<String> = Vec::new();
+    for candidate in candidates() {
+        let version = match java_version(&candidate) {
+            Some(version) => version,
+            None => continue,
+        };
+        if required.is_none_or(|required| version >= required) {
+            return Ok(candidate.display().to_string());
+        }
+        checked.push(format!("{} (Java {version})", candidate.display()));
+    }
+
+    match required {
+        Some(required) if !checked.is_empty() => bail!(
+            "Java {required} or newer not found, set launch.java.path. Checked:\n{}",
+            checked.join("\n")
+        ),
+        Some(required) => bail!("Java {required} or newer not found, set launch.java.path"),
+        None => bail!("Java not found, set launch.java.path"),
+    }
+}
+
+// Platform of mcstarter in Adoptium terms, e.g. `linux-x64`
+pub fn current_platform() -> String {
+    let os = match env::consts::OS {
+        "macos" => "mac",
+        os => os,
+    };
+    let arch = match env::consts::ARCH {
+        "x86_64" => "x64",
+        arch => arch,
+    };
+    format!("{os}-{arch}")
+}
+
+// URL of JDK archive for platform
+pub fn jdk_url(download: &JavaDownload, platform: &str) -> Result<String> {
+    match (&download.url, &download.release) {
+        (Some(url), None) => Ok(url.clone()),
+        (None, Some(release)) => {
+            let Some((os, arch)) = platform.split_once('-') else {
+                bail!("invalid platform {platform}, expected OS-ARCH like linux-x64");
+            };
+            Ok(format!(
+                "{}/v3/binary/version/{}/{os}/{arch}/jdk/hotspot/normal/eclipse",
+                download.api.trim_end_matches('/'),
+                release.replace('+', "%2B")
+            ))
+        }
+        _ => bail!("launch.java.download must have either url or release"),
+    }
+}
+
+// Lock entry of JDK, release is locked per platform
+fn jdk_key(download: &JavaDownload, platform: &str) -> Result<String> {
+    match &download.release {
+        Some(release) if download.url.is_none() => Ok(java_key(&format!("{release}:{platform}"))),
+        _ => Ok(java_key(&jdk_url(download, platform)?)),
+    }
+}
+
+// Add hashes of JDK to lock if they're not locked yet, release for all platforms
+pub async fn lock_jdk(download: &JavaDownload, lock: &mut HashMap<String, String>) -> Result<()> {
+    let mut platforms = vec![current_platform()];
+    if download.url.is_none() {
+        platforms.extend(download.platforms.iter().cloned());
+    }
+
+    for platform in platforms {
+        if let Entry::Vacant(entry) = lock.entry(jdk_key(download, &platform)?) {
+            let url = jdk_url(download, &platform)?;
+            println!("Downloading {url}");
+            let bytes = download_url(&url).await?;
+            entry.insert(hash_bytes(&bytes));
+        }
+    }
+    Ok(())
+}
+
+// Download locked JDK and unpack it into cache
+pub async fn cache_jdk(
+    download: &JavaDownload,
+    lock: &HashMap<String, String>,
+    cache: &str,
+) -> Result<()> {
+    let platform = current_platform();
+    let url = jdk_url(download, &platform)?;
+    let hash = get_lock_entry(&jdk_key(download, &platform)?, lock)?;
+
+    let dir = Path::new(cache).join(format!("jdk-{hash}"));
+    if !dir.exists() {
+        println!("Downloading {url}");
+        let bytes = download_url(&url).await?;
+        if hash_bytes(&bytes) != hash {
+            bail!("JDK {url} has invalid hash");
+        }
+        unpack_tarball(&bytes, &dir)?;
+    }
+    Ok(())
+}
+
+// Java of JDK in cache
+fn jdk_java(
+    download: &JavaDownload,
+    lock: &HashMap<String, String>,
+    cache: &str,
+) -> Result<String> {
+    let hash = get_lock_entry(&jdk_key(download, &current_platform())?, lock)?;
+    let dir = Path::new(cache).join(format!("jdk-{hash}"));
+    // macOS JDKs are bundles
+    for java in ["bin/java", "Contents/Home/bin/java"] {
+        // Launch changes directory, so path must be absolute
+        if dir.join(java).is_file() {
+            return Ok(fs::canonicalize(dir.join(java))?.display().to_string());
+        }
+    }
+    bail!("JDK is not in cache, run mcstarter download")
+}
+
+// Minimal Java version for Minecraft version, None if it's unknown
+fn required_version(minecraft: &str) -> Option<u32> {
+    let (minor, patch) = minecraft_version(minecraft)?;
+
+    let version = match (minor, patch) {
+        (..=16, _) => 8,
+        (17, _) => 16,
+        (18..=19, _) | (20, ..=4) => 17,
+        _ => 21,
+    };
+    Some(version)
+}
+
+// Minor and patch of `1.20.4` or `1.19-34` core version
+pub fn minecraft_version(version: &str) -> Option<(u32, u32)> {
+    let captures = MINECRAFT_VERSION_REGEX.captures(version)?;
+    let minor: u32 = captures["minor"].parse().ok()?;
+    let patch: u32 = captures
+        .name("patch")
+        .map_or(Some(0), |patch| patch.as_str().parse().ok())?;
+    Some((minor, patch))
+}
+
+// JAVA_HOME, PATH and JDK directories, in order of preference
+fn candidates() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+
+    if let Some(java_home) = env::var_os("JAVA_HOME") {
+        candidates.push(Path::new(&java_home).join("bin/java"));
+    }
+    if let Some(path) = env::var_os("PATH") {
+        candidates.extend(env::split_paths(&path).map(|dir| dir.join("java")));
+    }
+
+    for dir in JDK_DIRS {
+        let mut jdks: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(_) => continue,
+        };
+        // Newer JDKs are usually sorted last
+        jdks.sort();
+        for jdk in jdks.into_iter().rev() {
+            candidates.push(jdk.join("bin/java"));
+            candidates.push(jdk.join("Contents/Home/bin/java"));
+        }
+    }
+
+    // Same java is often linked from several places
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    candidates
+        .into_iter()
+        .filter(|c| fs::canonicalize(c).is_ok_and(|c| seen.insert(c)))
+        .collect()
+}
+
+// Major version from `java -version`
+fn java_version(java: &Path) -> Option<u32> {
+    let output = Command::new(java).arg("-version").output().ok()?;
+    // Printed to stderr, but some builds use stdout
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stderr),
+        String::from_utf8_lossy(&output.stdout)
+    );
+    VERSION_REGEX.captures(&text)?["major"].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    const FAKE_JAVA: &str = "#!/bin/sh\necho 'openjdk version \"17.0.8\" 2023-07-18' >&2\n";
+
+    // JDK-like archive with single top-level directory
+    fn jdk_tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(FAKE_JAVA.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "jdk-17.0.8+7/bin/java", FAKE_JAVA.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    // Serve body on every request, returns base URL
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    stream.write_all(&body).await.unwrap();
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn temp_cache(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("mcstarter-{name}-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        dir.display().to_string()
+    }
+
+    fn core(version: &str) -> Core {
+        Core {
+            name: String::from("paper"),
+            version: String::from(version),
+            source: None,
+            url: None,
+        }
+    }
+
+    #[test]
+    fn adoptium_url() {
+        let download = JavaDownload {
+            url: None,
+            release: Some(String::from("jdk-21.0.4+7")),
+            api: String::from("http://127.0.0.1/"),
+            platforms: Vec::new(),
+        };
+        let url = jdk_url(&download, "mac-aarch64").unwrap();
+        assert_eq!(
+            url,
+            "http://127.0.0.1/v3/binary/version/jdk-21.0.4%2B7/mac/aarch64/jdk/hotspot/normal/eclipse"
+        );
+        assert!(jdk_url(&download, "linux").is_err());
+    }
+
+    #[test]
+    fn java_version_of_minecraft() {
+        assert_eq!(required_version("1.12.2"), Some(8));
+        assert_eq!(required_version("1.17.1"), Some(16));
+        assert_eq!(required_version("1.19-34"), Some(17));
+        assert_eq!(required_version("1.20.4"), Some(17));
+        assert_eq!(required_version("1.20.5"), Some(21));
+        assert_eq!(required_version("latest"), None);
+    }
+
+    #[tokio::test]
+    async fn downloads_locked_jdk() {
+        let tarball = jdk_tarball();
+        let download = JavaDownload {
+            url: Some(format!("{}/jdk.tar.gz", serve(tarball.clone()).await)),
+            release: None,
+            api: String::new(),
+            platforms: Vec::new(),
+        };
+        let cache = temp_cache("jdk");
+
+        let mut lock = HashMap::new();
+        lock_jdk(&download, &mut lock).await.unwrap();
+        assert_eq!(
+            lock.get(&java_key(download.url.as_ref().unwrap())),
+            Some(&hash_bytes(&Bytes::from(tarball)))
+        );
+
+        let java = Java {
+            path: None,
+            version: None,
+            download: Some(download),
+        };
+        assert!(find_java(&java, &core("1.19"), &lock, &cache, Path::new("./")).is_err());
+
+        cache_jdk(java.download.as_ref().unwrap(), &lock, &cache)
+            .await
+            .unwrap();
+        let path = find_java(&java, &core("1.19"), &lock, &cache, Path::new("./")).unwrap();
+        assert!(path.ends_with("/bin/java"));
+        assert!(Path::new(&path).metadata().unwrap().permissions().mode() & 0o111 != 0);
+
+        // Required version is still checked
+        assert!(find_java(&java, &core("1.21"), &lock, &cache, Path::new("./")).is_err());
+
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[tokio::test]
+    async fn locks_release_for_all_platforms() {
+        let tarball = jdk_tarball();
+        let download = JavaDownload {
+            url: None,
+            release: Some(String::from("jdk-21.0.4+7")),
+            api: serve(tarball.clone()).await,
+            platforms: vec![String::from("linux-x64"), String::from("mac-aarch64")],
+        };
+        let cache = temp_cache("jdk-release");
+
+        let mut lock = HashMap::new();
+        lock_jdk(&download, &mut lock).await.unwrap();
+        for platform in ["linux-x64", "mac-aarch64", &current_platform()] {
+            assert_eq!(
+                lock.get(&java_key(&format!("jdk-21.0.4+7:{platform}"))),
+                Some(&hash_bytes(&Bytes::from(tarball.clone())))
+            );
+        }
+
+        cache_jdk(&download, &lock, &cache).await.unwrap();
+        assert!(jdk_java(&download, &lock, &cache).is_ok());
+
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_jdk_with_invalid_hash() {
+        let url = format!("{}/jdk.tar.gz", serve(jdk_tarball()).await);
+        let download = JavaDownload {
+            url: Some(url.clone()),
+            release: None,
+            api: String::new(),
+            platforms: Vec::new(),
+        };
+        let cache = temp_cache("jdk-invalid");
+
+        let mut lock = HashMap::new();
+        lock.insert(java_key(&url), hash_bytes(&Bytes::from_static(b"other")));
+        assert!(cache_jdk(&download, &lock, &cache).await.is_err());
+        assert!(!Path::new(&cache)
+            .join(format!("jdk-{}", hash_bytes(&Bytes::from_static(b"other"))))
+            .exists());
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{self, Path};
+
+use crate::config::Config;
+use crate::java::find_java;
+use crate::lock::{core_key, get_lock_entry};
+use crate::memory::memory_args;
+
+// Fully resolved command which runs server
+#[derive(Serialize)]
+pub struct LaunchCommand {
+    pub java: String,
+    pub args: Vec<String>,
+    // Absolute server directory
+    pub dir: String,
+    // Variables added to environment of mcstarter
+    pub env: BTreeMap<String, String>,
+}
+
+impl LaunchCommand {
+    pub fn new(
+        config: &Config,
+        lock: &HashMap<String, String>,
+        cache: &str,
+        target: &str,
+        root: &Path,
+    ) -> Result<LaunchCommand> {
+        let core_hash = get_lock_entry(&core_key(&config.core), lock)?;
+        let mut java = find_java(&config.launch.java, &config.core, lock, cache, root)?;
+        // Server runs in target directory, so relative path won't work there
+        if java.contains('/') {
+            java = path::absolute(Path::new(&java))?.display().to_string();
+        }
+
+        let mut args = memory_args(&config.launch)?;
+        args.extend(config.launch.java_args.iter().cloned());
+        args.push(String::from("-jar"));
+        args.push(format!("core-{core_hash}.jar"));
+        args.extend(config.launch.mc_args.iter().cloned());
+
+        Ok(LaunchCommand {
+            java,
+            args,
+            dir: path::absolute(Path::new(target))?.display().to_string(),
+            env: config.launch.env.clone().into_iter().collect(),
+        })
+    }
+
+    // Java and its arguments, quoted for shell
+    pub fn argv(&self) -> String {
+        let mut argv = vec![quote(&self.java)];
+        argv.extend(self.args.iter().map(|arg| quote(arg)));
+        argv.join(" ")
+    }
+
+    // Script which runs server
+    pub fn script(&self) -> String {
+        let mut script = format!("cd {} && exec ", quote(&self.dir));
+        if !self.env.is_empty() {
+            script.push_str("env ");
+            for (key, value) in &self.env {
+                script.push_str(&quote(&format!("{key}={value}")));
+                script.push(' ');
+            }
+        }
+        script.push_str(&self.argv());
+        script
+    }
+}
+
+// Single-quote argument unless it's safe for shell as is
+fn quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=+.,:/@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
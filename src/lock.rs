@@ -1,25 +1,119 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 use std::fs;
+use std::path::Path;
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 
-pub fn load_lock() -> Result<HashMap<String, String>> {
-    let lock_file = fs::read_to_string("./mcstarter.lock")?;
+use crate::config::{Config, Core, Datapack, Plugin};
+use crate::download::{download_core, download_plugin, download_url};
+use crate::hash::hash_bytes;
+use crate::java::lock_jdk;
+
+pub fn load_lock(path: &Path) -> Result<HashMap<String, String>> {
+    let lock_file = fs::read_to_string(path)
+        .with_context(|| format!("can't read {}, run mcstarter lock", path.display()))?;
     let lock: HashMap<String, String> = serde_yaml::from_str(&lock_file)?;
+    // Before entries were keyed by version, core was locked as `core`
+    if lock.contains_key("core") {
+        bail!("{} has old format, run mcstarter lock", path.display());
+    }
     Ok(lock)
 }
 
-pub fn save_lock(lock: &HashMap<String, String>) -> Result<()> {
-    let lock = serde_yaml::to_string(lock)?;
-    fs::write("./mcstarter.lock", lock)?;
+pub fn save_lock(path: &Path, lock: &HashMap<String, String>) -> Result<()> {
+    // Sorted, so lock diffs are readable
+    let lock: BTreeMap<&String, &String> = lock.iter().collect();
+    let lock = serde_yaml::to_string(&lock)?;
+    fs::write(path, lock)?;
     Ok(())
 }
 
-pub fn get_lock_entry(name: &String, lock: &HashMap<String, String>) -> Result<String> {
+pub fn get_lock_entry(name: &str, lock: &HashMap<String, String>) -> Result<String> {
     let hash = lock.get(name);
     match hash {
         Some(h) => Ok(h.clone()),
-        None => Err(anyhow!("no entry {name} in lock")),
+        None => Err(anyhow!("no entry {name} in lock, run mcstarter lock")),
+    }
+}
+
+// Entries are keyed by version, so lock can hold artifacts of all profiles
+pub fn core_key(core: &Core) -> String {
+    format!("core:{}@{}", core.name, core.version)
+}
+
+pub fn plugin_key(name: &str, plugin: &Plugin) -> String {
+    format!("{name}@{}", plugin.version)
+}
+
+pub fn datapack_key(name: &str, datapack: &Datapack) -> String {
+    format!("datapack:{name}@{}", datapack.version)
+}
+
+pub fn resource_pack_key(url: &str) -> String {
+    format!("resource_pack:{url}")
+}
+
+pub fn world_template_key(url: &str) -> String {
+    format!("world_template:{url}")
+}
+
+pub fn java_key(url: &str) -> String {
+    format!("java:{url}")
+}
+
+// Add hashes of config's core and plugins which are not locked yet
+pub async fn lock_artifacts(config: &Config, lock: &mut HashMap<String, String>) -> Result<()> {
+    for (key, pin) in &config.remote_includes {
+        lock.insert(key.clone(), pin.clone());
     }
+
+    if let Entry::Vacant(entry) = lock.entry(core_key(&config.core)) {
+        let core_bytes = download_core(&config.core, config).await?;
+        entry.insert(hash_bytes(&core_bytes));
+    }
+
+    if let Some(download) = &config.launch.java.download {
+        lock_jdk(download, lock).await?;
+    }
+
+    for (name, plugin) in &config.plugins {
+        if let Entry::Vacant(entry) = lock.entry(plugin_key(name, plugin)) {
+            let plugin_bytes = download_plugin(name, plugin, config).await?;
+            entry.insert(hash_bytes(&plugin_bytes));
+        }
+    }
+
+    for (name, datapack) in &config.datapacks {
+        if let Entry::Vacant(entry) = lock.entry(datapack_key(name, datapack)) {
+            let datapack_bytes = download_url(&datapack.url).await?;
+            entry.insert(hash_bytes(&datapack_bytes));
+        }
+    }
+
+    if let Some(resource_pack) = &config.resource_pack {
+        if let Entry::Vacant(entry) = lock.entry(resource_pack_key(&resource_pack.url)) {
+            let pack_bytes = download_url(&resource_pack.url).await?;
+            entry.insert(hash_bytes(&pack_bytes));
+        }
+    }
+
+    if let Some(template) = &config.world_template {
+        if let Entry::Vacant(entry) = lock.entry(world_template_key(&template.url)) {
+            let template_bytes = download_url(&template.url).await?;
+            entry.insert(hash_bytes(&template_bytes));
+        }
+    }
+    Ok(())
+}
+
+// Remote includes must be the same as when lock was made
+pub fn check_includes(config: &Config, lock: &HashMap<String, String>) -> Result<()> {
+    for (key, pin) in &config.remote_includes {
+        if lock.get(key) != Some(pin) {
+            bail!("{key} is not locked, run mcstarter lock");
+        }
+    }
+    Ok(())
 }
@@ -1,27 +1,58 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use lock::get_lock_entry;
+use anyhow::{bail, Result};
+use clap::{ArgEnum, Parser, Subcommand};
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::env::set_current_dir;
 use std::fs::{self, create_dir_all};
 use std::include_str;
 use std::os::unix::process::CommandExt;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{self, Command};
 
 mod build;
 mod cache;
 mod config;
+mod cron;
 mod download;
+mod emitter;
 mod env;
 mod hash;
+mod include;
+mod java;
+mod launch;
 mod lock;
+mod memory;
 mod merger;
+mod project;
+mod properties;
+mod proxy;
+mod server;
+mod supervisor;
+mod workspace;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 struct Cli {
+    /// Profile from mcstarter.yml, also selects .env.<PROFILE> file
+    #[clap(long, global = true)]
+    profile: Option<String>,
+    /// Run as if mcstarter was started in DIRECTORY
+    #[clap(short = 'C', long, global = true)]
+    directory: Option<String>,
+    /// Config file, server files are taken from its directory
+    #[clap(long, global = true)]
+    config: Option<String>,
+    /// Lock file, next to config by default
+    #[clap(long, global = true)]
+    lock: Option<String>,
+    /// Run command on all members of workspace
+    #[clap(long, global = true)]
+    all: bool,
+    /// Run command on member of workspace
+    #[clap(short = 'p', long = "member", global = true)]
+    members: Vec<String>,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -34,103 +65,370 @@ enum Commands {
     Lock {},
     /// Download files to cache
     Download {
-        #[clap(default_value_t = String::from("./cache"))]
-        cache: String,
+        /// Cache directory [default: cache in project]
+        cache: Option<String>,
     },
     /// Build server
     Build {
-        /// Target directory
-        #[clap(default_value_t = String::from("./build"))]
-        target: String,
-        #[clap(default_value_t = String::from("./cache"))]
-        cache: String,
+        /// Target directory [default: build in project]
+        target: Option<String>,
+        /// Cache directory [default: cache in project]
+        cache: Option<String>,
     },
     /// Launch server
     Launch {
-        /// Target directory
-        #[clap(default_value_t = String::from("./build"))]
-        target: String,
+        /// Target directory [default: build in project]
+        target: Option<String>,
+        /// Cache directory [default: cache in project]
+        #[clap(long)]
+        cache: Option<String>,
+        /// Keep running and restart server on crashes
+        #[clap(long)]
+        supervise: bool,
+        /// Print command, directory and environment instead of launching, e.g. --dry-run=json
+        #[clap(
+            long,
+            arg_enum,
+            value_name = "FORMAT",
+            min_values = 0,
+            require_equals = true,
+            default_missing_value = "shell"
+        )]
+        dry_run: Option<DryRunFormat>,
+        /// What to do if server was changed after last build
+        #[clap(long, arg_enum, value_name = "POLICY", default_value = "refuse")]
+        stale: StalePolicy,
     },
+    /// Download, build and launch server
+    Up {
+        /// Target directory [default: build in project]
+        target: Option<String>,
+        /// Cache directory [default: cache in project]
+        #[clap(long)]
+        cache: Option<String>,
+        /// Keep running and restart server on crashes
+        #[clap(long)]
+        supervise: bool,
+    },
+    /// Attach to console of supervised server
+    Console {
+        /// Target directory [default: build in project]
+        target: Option<String>,
+    },
+    /// Send command to console of supervised server
+    Send {
+        /// Console command, e.g. "say hi"
+        #[clap(required = true)]
+        command: Vec<String>,
+        /// Target directory [default: build in project]
+        #[clap(long)]
+        target: Option<String>,
+    },
+}
+
+#[derive(Clone, ArgEnum)]
+enum DryRunFormat {
+    Json,
+    Shell,
+}
+
+#[derive(Clone, ArgEnum)]
+enum StalePolicy {
+    /// Exit with error
+    Refuse,
+    /// Launch anyway
+    Warn,
+    /// Build before launch
+    Build,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let project = project::Project::new(
+        cli.directory.as_deref(),
+        cli.config.as_deref(),
+        cli.lock.as_deref(),
+    );
+    let projects = workspace::select_projects(
+        project,
+        cli.all,
+        &cli.members,
+        cli.config.is_some(),
+        cli.lock.is_some(),
+    )?;
+    let profile = cli.profile.as_deref();
 
     match &cli.command {
         Commands::Init {} => {
-            let default_config = include_str!("mcstarter.yml");
-            fs::write(format!("./mcstarter.yml"), default_config)?;
-            println!("Initialized mcstarter.yml")
+            for project in &projects {
+                let default_config = include_str!("mcstarter.yml");
+                fs::write(&project.config, default_config)?;
+                println!("Initialized {}", project.config.display())
+            }
         }
 
         Commands::Lock {} => {
             println!("Locking...");
-            let config = config::load_config(false)?;
-
-            let core_bytes = download::download_core(&config.core, &config).await?;
-            let core_hash = hash::hash_bytes(&core_bytes);
-
-            let mut lock: HashMap<String, String> = HashMap::new();
-
-            lock.insert(String::from("core"), core_hash);
-
-            for (name, plugin) in &config.plugins {
-                let plugin_bytes = download::download_plugin(name, &plugin, &config).await?;
-                let plugin_hash = hash::hash_bytes(&plugin_bytes);
-                lock.insert(name.clone(), plugin_hash);
+            // Members share lock, so locking some of them keeps entries of others
+            let mut locks: HashMap<PathBuf, HashMap<String, String>> = HashMap::new();
+            if let Some(member) = projects.iter().find(|project| project.member.is_some()) {
+                workspace::check_members_plugins(member).await?;
+            }
+            for project in &projects {
+                print_member(project, "Locking");
+                let lock = match locks.entry(project.lock.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        if project.member.is_some() && !cli.all && project.lock.exists() {
+                            entry.insert(lock::load_lock(&project.lock)?)
+                        } else {
+                            entry.insert(HashMap::new())
+                        }
+                    }
+                };
+                lock_project(project, lock).await?;
             }
 
-            lock::save_lock(&lock)?;
+            for (path, lock) in &locks {
+                lock::save_lock(path, lock)?;
+            }
             println!("Done!");
         }
 
         Commands::Download { cache } => {
-            let config = config::load_config(false)?;
-            let lock = lock::load_lock()?;
+            for project in &projects {
+                print_member(project, "Downloading");
+                let cache = &project.arg_path(cache, &project.cache);
+                download_project(project, profile, cache).await?;
+            }
+        }
 
-            create_dir_all(cache)?;
+        Commands::Build { target, cache } => {
+            if target.is_some() && projects.len() > 1 {
+                bail!("TARGET can't be used with several members");
+            }
+            for project in &projects {
+                print_member(project, "Building");
+                let vars = env::Vars::load(&project.root, profile)?;
+                let target = &project.arg_path(target, &project.path(build::DEFAULT_TARGET_DIR));
+                let cache = &project.arg_path(cache, &project.cache);
+                let config = config::load_config(project, true, &vars, profile, cache).await?;
+                let lock = lock::load_lock(&project.lock)?;
+                build_project(project, &config, profile, &vars, &lock, target, cache).await?;
+            }
+        }
+
+        Commands::Launch {
+            target,
+            cache,
+            supervise,
+            dry_run,
+            stale,
+        } => {
+            let project = single_project(&projects)?;
+            let vars = env::Vars::load(&project.root, profile)?;
+            let target = &project.arg_path(target, &project.path(build::DEFAULT_TARGET_DIR));
+            let cache = &project.arg_path(cache, &project.cache);
+            let config = config::load_config(project, true, &vars, profile, cache).await?;
+            let lock = lock::load_lock(&project.lock)?;
+
+            let command = launch::LaunchCommand::new(&config, &lock, cache, target, &project.root)?;
+
+            if let Some(format) = dry_run {
+                match format {
+                    DryRunFormat::Json => println!("{}", serde_json::to_string_pretty(&command)?),
+                    DryRunFormat::Shell => println!("{}", command.script()),
+                }
+                return Ok(());
+            }
 
-            cache::cache_core(&config.core, &config, &lock, cache).await?;
-            cache::cache_plugins(&config.plugins, &config, &lock, cache).await?;
+            match build::is_up_to_date(project, &config, profile, &lock, target, cache)? {
+                Some(true) => {}
+                // Built by older mcstarter or not built yet
+                None => println!("Can't check if server in {target} is built from current files, run mcstarter build to be sure"),
+                Some(false) => match stale {
+                    StalePolicy::Refuse => bail!(
+                        "server in {target} is not built from current files, run mcstarter build or use --stale build"
+                    ),
+                    StalePolicy::Warn => {
+                        println!("Server in {target} is not built from current files, launching anyway")
+                    }
+                    StalePolicy::Build => {
+                        println!("Server in {target} is not built from current files, building...");
+                        build_project(project, &config, profile, &vars, &lock, target, cache)
+                            .await?;
+                    }
+                },
+            }
+
+            launch_server(command, &config, *supervise).await?;
         }
 
-        Commands::Build { target, cache } => {
-            let config = config::load_config(true)?;
-            let lock = lock::load_lock()?;
+        Commands::Up {
+            target,
+            cache,
+            supervise,
+        } => {
+            let project = single_project(&projects)?;
+            let cache = &project.arg_path(cache, &project.cache);
+            download_project(project, profile, cache).await?;
 
-            create_dir_all(target)?;
+            let vars = env::Vars::load(&project.root, profile)?;
+            let target = &project.arg_path(target, &project.path(build::DEFAULT_TARGET_DIR));
+            let config = config::load_config(project, true, &vars, profile, cache).await?;
+            let lock = lock::load_lock(&project.lock)?;
+            build_project(project, &config, profile, &vars, &lock, target, cache).await?;
 
-            build::build_core(&lock, &target, &cache).await?;
-            build::build_plugins(&config.plugins, &lock, &target, &cache).await?;
-            build::build_files(&config.include, &target).await?;
+            let command = launch::LaunchCommand::new(&config, &lock, cache, target, &project.root)?;
+            launch_server(command, &config, *supervise).await?;
         }
 
-        Commands::Launch { target } => {
-            let config = config::load_config(true)?;
-            let lock = lock::load_lock()?;
+        Commands::Console { target } => {
+            let project = single_project(&projects)?;
+            let target = &project.arg_path(target, &project.path(build::DEFAULT_TARGET_DIR));
+            supervisor::console(target).await?;
+        }
+
+        Commands::Send { command, target } => {
+            let project = single_project(&projects)?;
+            let target = &project.arg_path(target, &project.path(build::DEFAULT_TARGET_DIR));
+            supervisor::send(target, &command.join(" ")).await?;
+        }
+    }
+    Ok(())
+}
 
-            let core_hash = get_lock_entry(&String::from("core"), &lock)?;
+// Lock artifacts of all profiles, so switching profile doesn't need relock
+async fn lock_project(
+    project: &project::Project,
+    lock: &mut HashMap<String, String>,
+) -> Result<()> {
+    let vars = env::Vars::load(&project.root, None)?;
+    let config = config::load_config(project, false, &vars, None, &project.cache).await?;
+    lock::lock_artifacts(&config, lock).await?;
+    if let Some(settings) = &config.proxy {
+        proxy::ensure_secret(project, settings)?;
+    }
 
-            set_current_dir(target)?;
+    for profile in config.profiles.keys() {
+        let profile_config =
+            config::load_config(project, false, &vars, Some(profile), &project.cache).await?;
+        lock::lock_artifacts(&profile_config, lock).await?;
+    }
+    Ok(())
+}
 
-            let mut args = config.launch.java_args;
-            args.push_back(String::from("-jar"));
-            args.push_back(format!("core-{core_hash}.jar"));
+async fn download_project(
+    project: &project::Project,
+    profile: Option<&str>,
+    cache: &String,
+) -> Result<()> {
+    let vars = env::Vars::load(&project.root, profile)?;
+    let config = config::load_config(project, false, &vars, profile, cache).await?;
+    let lock = lock::load_lock(&project.lock)?;
+    lock::check_includes(&config, &lock)?;
 
-            let mut mc_args = config.launch.mc_args;
-            args.append(&mut mc_args);
+    create_dir_all(cache)?;
 
-            print!("Running java");
-            for arg in &args {
-                print!(" {}", arg);
-            }
+    cache::cache_core(&config.core, &config, &lock, cache).await?;
+    cache::cache_plugins(&config.plugins, &config, &lock, cache).await?;
+    cache::cache_datapacks(&config.datapacks, &lock, cache).await?;
+    if let Some(resource_pack) = &config.resource_pack {
+        cache::cache_resource_pack(resource_pack, &lock, cache).await?;
+    }
+    if let Some(template) = &config.world_template {
+        cache::cache_world_template(template, &lock, cache).await?;
+    }
+    if let Some(download) = &config.launch.java.download {
+        java::cache_jdk(download, &lock, cache).await?;
+    }
+    Ok(())
+}
 
-            println!();
+async fn build_project(
+    project: &project::Project,
+    config: &config::Config,
+    profile: Option<&str>,
+    vars: &env::Vars,
+    lock: &HashMap<String, String>,
+    target: &String,
+    cache: &String,
+) -> Result<()> {
+    lock::check_includes(config, lock)?;
 
-            Command::new("java").args(args).exec();
-            panic!("can't launch")
-        }
+    create_dir_all(target)?;
+    if let Some(settings) = &config.proxy {
+        proxy::ensure_secret(project, settings)?;
     }
+
+    build::build_core(&config.core, lock, target, cache).await?;
+    build::build_plugins(&config.plugins, lock, target, cache).await?;
+    build::build_files(project, config, vars, lock, target, cache).await?;
+    build::build_world(config, lock, target, cache)?;
+    build::build_datapacks(config, lock, target, cache).await?;
+    let fingerprint = build::fingerprint(project, config, profile, lock, target, cache)?;
+    build::save_fingerprint(target, &fingerprint)?;
     Ok(())
 }
+
+// Never returns, server replaces mcstarter unless it's supervised
+async fn launch_server(
+    command: launch::LaunchCommand,
+    config: &config::Config,
+    supervise: bool,
+) -> Result<()> {
+    set_current_dir(&command.dir)?;
+    println!("Running {}", command.argv());
+
+    if supervise || config.launch.supervisor.enabled {
+        let code = supervisor::supervise(&command, &config.launch, &config.schedule).await?;
+        process::exit(code);
+    }
+    if !config.schedule.is_empty() {
+        println!("Schedule works only with --supervise, ignoring it");
+    }
+
+    let err = Command::new(command.java)
+        .args(command.args)
+        .envs(command.env)
+        .exec();
+    bail!("can't launch: {err}")
+}
+
+fn single_project(projects: &[project::Project]) -> Result<&project::Project> {
+    match projects {
+        [project] => Ok(project),
+        _ => bail!("command can't be run on several members at once"),
+    }
+}
+
+fn print_member(project: &project::Project, action: &str) {
+    if let Some(member) = &project.member {
+        println!("{action} {member}...");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_keeps_target() {
+        let cli = Cli::try_parse_from(["mcstarter", "launch", "--dry-run", "out"]).unwrap();
+        let Commands::Launch {
+            target, dry_run, ..
+        } = cli.command
+        else {
+            panic!("expected launch");
+        };
+        assert_eq!(target.as_deref(), Some("out"));
+        assert!(matches!(dry_run, Some(DryRunFormat::Shell)));
+
+        let cli = Cli::try_parse_from(["mcstarter", "launch", "--dry-run=json"]).unwrap();
+        let Commands::Launch { dry_run, .. } = cli.command else {
+            panic!("expected launch");
+        };
+        assert!(matches!(dry_run, Some(DryRunFormat::Json)));
+    }
+}
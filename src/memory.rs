@@ -0,0 +1,205 @@
+use anyhow::{anyhow, bail, Result};
+
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Launch, Preset};
+
+const MIB: u64 = 1024 * 1024;
+
+// Values above this mean there's no limit, cgroup v1 uses ~2^63
+const NO_LIMIT: u64 = 1 << 60;
+
+// Aikar's flags, https://docs.papermc.io/paper/aikars-flags
+const AIKAR_FLAGS: [&str; 15] = [
+    "-XX:+UseG1GC",
+    "-XX:+ParallelRefProcEnabled",
+    "-XX:MaxGCPauseMillis=200",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:+DisableExplicitGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:G1HeapWastePercent=5",
+    "-XX:G1MixedGCCountTarget=4",
+    "-XX:G1MixedGCLiveThresholdPercent=90",
+    "-XX:G1RSetUpdatingPauseTimePercent=5",
+    "-XX:SurvivorRatio=32",
+    "-XX:+PerfDisableSharedMem",
+    "-XX:MaxTenuringThreshold=1",
+    "-Dusing.aikars.flags=https://mcflags.emc.gs",
+    "-Daikars.new.flags=true",
+];
+
+// Aikar's flags which depend on heap size, for less and more than 12G
+const AIKAR_SMALL_HEAP_FLAGS: [&str; 5] = [
+    "-XX:G1NewSizePercent=30",
+    "-XX:G1MaxNewSizePercent=40",
+    "-XX:G1HeapRegionSize=8M",
+    "-XX:G1ReservePercent=20",
+    "-XX:InitiatingHeapOccupancyPercent=15",
+];
+const AIKAR_LARGE_HEAP_FLAGS: [&str; 5] = [
+    "-XX:G1NewSizePercent=40",
+    "-XX:G1MaxNewSizePercent=50",
+    "-XX:G1HeapRegionSize=16M",
+    "-XX:G1ReservePercent=15",
+    "-XX:InitiatingHeapOccupancyPercent=20",
+];
+
+const ZGC_FLAGS: [&str; 4] = [
+    "-XX:+UseZGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:+DisableExplicitGC",
+    "-XX:+PerfDisableSharedMem",
+];
+
+// Heap and GC flags from launch.memory and launch.preset, go before java_args
+pub fn memory_args(launch: &Launch) -> Result<Vec<String>> {
+    // Outside of containers limit is physical memory
+    let limit = memory_limit().or_else(total_memory);
+    let heap = match &launch.memory {
+        Some(memory) => Some(heap_size(memory, limit)?),
+        None => None,
+    };
+
+    if let (Some(heap), Some(limit)) = (heap, limit) {
+        // JVM needs memory besides heap, for metaspace, threads and GC
+        if heap >= limit {
+            bail!(
+                "heap of {}M leaves no room below memory limit of {}M",
+                heap / MIB,
+                limit / MIB
+            );
+        }
+        if heap > limit / 10 * 9 {
+            println!(
+                "Heap of {}M is more than 90% of memory limit of {}M, server may be killed",
+                heap / MIB,
+                limit / MIB
+            );
+        }
+    }
+
+    let mut args: Vec<String> = Vec::new();
+    if let Some(heap) = heap {
+        // Whole heap is allocated at start, like Aikar recommends
+        args.push(format!("-Xms{}M", heap / MIB));
+        args.push(format!("-Xmx{}M", heap / MIB));
+    }
+
+    match launch.preset {
+        Preset::Aikar => {
+            args.extend(AIKAR_FLAGS.iter().map(|flag| flag.to_string()));
+            let heap_flags = match heap {
+                Some(heap) if heap >= 12 * 1024 * MIB => AIKAR_LARGE_HEAP_FLAGS,
+                _ => AIKAR_SMALL_HEAP_FLAGS,
+            };
+            args.extend(heap_flags.iter().map(|flag| flag.to_string()));
+        }
+        Preset::Zgc => args.extend(ZGC_FLAGS.iter().map(|flag| flag.to_string())),
+        Preset::None => {}
+    }
+    Ok(args)
+}
+
+// Heap size in bytes from `4G`, `512M` or `75%` of memory limit
+fn heap_size(memory: &str, limit: Option<u64>) -> Result<u64> {
+    let memory = memory.trim();
+    if let Some(percent) = memory.strip_suffix('%') {
+        let percent: u64 = percent
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid memory {memory}"))?;
+        if percent == 0 || percent >= 100 {
+            bail!("memory {memory} must be between 1% and 99%");
+        }
+        let total = match limit {
+            Some(total) => total,
+            None => bail!("can't detect memory limit for memory {memory}"),
+        };
+        return Ok(total / 100 * percent);
+    }
+
+    let (number, unit) = match memory.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => memory.split_at(i),
+        None => (memory, ""),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid memory {memory}"))?;
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => MIB,
+        "G" | "GB" => 1024 * MIB,
+        "T" | "TB" => 1024 * 1024 * MIB,
+        _ => bail!("invalid memory {memory}, expected e.g. 4G, 512M or 75%"),
+    };
+    // Heap is passed to java in megabytes
+    if number * multiplier < MIB {
+        bail!("memory {memory} is less than 1M");
+    }
+    Ok(number * multiplier)
+}
+
+// Memory limit of cgroup v2 or v1 which mcstarter runs in
+fn memory_limit() -> Option<u64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+
+    let mut files: Vec<String> = Vec::new();
+    for line in cgroups.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+        if controllers.is_empty() {
+            files.push(format!("/sys/fs/cgroup{path}/memory.max"));
+        } else if controllers.split(',').any(|c| c == "memory") {
+            files.push(format!("/sys/fs/cgroup/memory{path}/memory.limit_in_bytes"));
+        }
+    }
+    // Containers usually see their own cgroup as root
+    files.push(String::from("/sys/fs/cgroup/memory.max"));
+    files.push(String::from("/sys/fs/cgroup/memory/memory.limit_in_bytes"));
+
+    files
+        .iter()
+        .filter_map(|file| read_limit(Path::new(file)))
+        .min()
+}
+
+fn read_limit(file: &Path) -> Option<u64> {
+    // `max` in cgroup v2 means there's no limit
+    let limit: u64 = fs::read_to_string(file).ok()?.trim().parse().ok()?;
+    if limit < NO_LIMIT {
+        Some(limit)
+    } else {
+        None
+    }
+}
+
+fn total_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_heap_size() {
+        assert_eq!(heap_size("4G", None).unwrap(), 4096 * MIB);
+        assert_eq!(heap_size("512m", None).unwrap(), 512 * MIB);
+        assert_eq!(heap_size("2048K", None).unwrap(), 2 * MIB);
+        assert_eq!(heap_size("50%", Some(1000 * MIB)).unwrap(), 500 * MIB);
+    }
+
+    #[test]
+    fn rejects_invalid_heap_size() {
+        assert!(heap_size("512K", None).is_err());
+        assert!(heap_size("0", None).is_err());
+        assert!(heap_size("4X", None).is_err());
+        assert!(heap_size("100%", Some(1024 * MIB)).is_err());
+        assert!(heap_size("50%", None).is_err());
+    }
+}
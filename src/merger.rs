@@ -1,50 +1,487 @@
-use linked_hash_map::Entry;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
 use yaml_rust::yaml::Hash;
 use yaml_rust::yaml::Yaml;
+use yaml_rust::YamlLoader;
+
+use std::path::Path;
+
+use crate::emitter::emit_yaml;
+use crate::properties::Properties;
+
+// Key holding merge directive, e.g. `__merge: replace`
+const MERGE_KEY: &str = "__merge";
+// Key holding value when directive is applied to non-hash, e.g. list
+const VALUE_KEY: &str = "__value";
+
+// Format of config which can be merged from several includes
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+    Properties,
+    Hocon,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let format = match path.extension()?.to_str()? {
+            "yml" | "yaml" => Format::Yaml,
+            "json" => Format::Json,
+            "toml" => Format::Toml,
+            "properties" => Format::Properties,
+            "conf" => Format::Hocon,
+            _ => return None,
+        };
+        Some(format)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Strategy {
+    // Deep merge hashes, append lists, replace scalars (default)
+    Merge,
+    // Replace previous value entirely
+    Replace,
+    // Remove key from result
+    Delete,
+    // Put new list items before old ones
+    Prepend,
+    // Append new list items, skipping duplicates
+    Unique,
+}
+
+impl Strategy {
+    fn parse(value: &Yaml) -> Result<Strategy> {
+        let strategy = match value.as_str() {
+            Some("merge") | Some("append") => Strategy::Merge,
+            Some("replace") => Strategy::Replace,
+            Some("delete") => Strategy::Delete,
+            Some("prepend") => Strategy::Prepend,
+            Some("unique") => Strategy::Unique,
+            _ => bail!("unknown merge strategy {value:?}"),
+        };
+        Ok(strategy)
+    }
+}
 
 // Merge two YAMLs
-pub fn merge_yamls(a: &Yaml, b: &Yaml) -> Yaml {
-    if let Yaml::Hash(a_hash) = a {
-        if let Yaml::Hash(b_hash) = b {
-            let c = merge_hashes(a_hash, b_hash);
-            Yaml::Hash(c)
-        } else {
-            b.clone()
+pub fn merge_yamls(a: &Yaml, b: &Yaml) -> Result<Yaml> {
+    Ok(merge_values(Some(a), b)?.unwrap_or(Yaml::Null))
+}
+
+// Merge b over a, None means value was deleted
+fn merge_values(a: Option<&Yaml>, b: &Yaml) -> Result<Option<Yaml>> {
+    let (strategy, b) = split_directive(b)?;
+
+    let merged = match (strategy, a, b) {
+        (Strategy::Delete, _, _) => None,
+        (Strategy::Replace, _, b) => resolve(b, Strategy::Replace)?,
+        (_, Some(Yaml::Hash(a_hash)), Yaml::Hash(b_hash)) => {
+            Some(Yaml::Hash(merge_hashes(a_hash, b_hash)?))
         }
-    } else {
-        b.clone()
-    }
+        (strategy, Some(Yaml::Array(a_vec)), Yaml::Array(b_vec)) => {
+            let b_vec = resolve_items(b_vec)?;
+            let vector = match strategy {
+                Strategy::Prepend => b_vec.into_iter().chain(a_vec.iter().cloned()).collect(),
+                _ => a_vec.iter().cloned().chain(b_vec).collect(),
+            };
+            if strategy == Strategy::Unique {
+                Some(Yaml::Array(dedup(vector)))
+            } else {
+                Some(Yaml::Array(vector))
+            }
+        }
+        (strategy, _, b) => resolve(b, strategy)?,
+    };
+    Ok(merged)
 }
 
 // Merge two YAML hashes
-fn merge_hashes(a: &Hash, b: &Hash) -> Hash {
+fn merge_hashes(a: &Hash, b: &Hash) -> Result<Hash> {
     let mut c = a.clone();
     for (b_k, b_v) in b {
-        // If b_v is dict
-        if let Yaml::Hash(b_v_hash) = b_v {
-            // If b_k exists in c (originally a)
-            if let Entry::Occupied(e) = c.entry(b_k.clone()) {
-                // If c[b_k] also dict
-                if let Yaml::Hash(mut c_hash) = e.get().clone() {
-                    c_hash = merge_hashes(&c_hash, b_v_hash);
-                    c.insert(b_k.clone(), Yaml::Hash(c_hash));
-                    continue;
+        if b_k.as_str() == Some(MERGE_KEY) {
+            continue;
+        }
+        match merge_values(c.get(b_k), b_v)? {
+            // Update in place to keep key order of a
+            Some(value) => match c.get_mut(b_k) {
+                Some(c_v) => *c_v = value,
+                None => {
+                    c.insert(b_k.clone(), value);
                 }
+            },
+            None => {
+                c.remove(b_k);
             }
         }
-        if b_v.is_array() {
-            if let Entry::Occupied(e) = c.entry(b_k.clone()) {
-                let cloned_e = e.get().clone();
-                if cloned_e.is_array() {
-                    let mut b_v_vector = b_v.as_vec().unwrap().clone();
-                    let mut vector = cloned_e.as_vec().unwrap().clone();
-                    vector.append(&mut b_v_vector);
-                    c.insert(b_k.clone(), Yaml::Array(vector));
-                    continue;
+    }
+    Ok(c)
+}
+
+// Apply directives to value which has nothing to merge with
+fn resolve(value: &Yaml, strategy: Strategy) -> Result<Option<Yaml>> {
+    let resolved = match value {
+        Yaml::Hash(hash) => Yaml::Hash(merge_hashes(&Hash::new(), hash)?),
+        Yaml::Array(vector) => {
+            let vector = resolve_items(vector)?;
+            if strategy == Strategy::Unique {
+                Yaml::Array(dedup(vector))
+            } else {
+                Yaml::Array(vector)
+            }
+        }
+        _ => value.clone(),
+    };
+    Ok(Some(resolved))
+}
+
+fn resolve_items(vector: &[Yaml]) -> Result<Vec<Yaml>> {
+    let mut items = Vec::with_capacity(vector.len());
+    for item in vector {
+        if let Some(item) = merge_values(None, item)? {
+            items.push(item);
+        }
+    }
+    Ok(items)
+}
+
+fn dedup(vector: Vec<Yaml>) -> Vec<Yaml> {
+    let mut unique: Vec<Yaml> = Vec::with_capacity(vector.len());
+    for item in vector {
+        if !unique.contains(&item) {
+            unique.push(item);
+        }
+    }
+    unique
+}
+
+// Extract `__merge` directive from value
+fn split_directive(value: &Yaml) -> Result<(Strategy, &Yaml)> {
+    if let Yaml::Hash(hash) = value {
+        if let Some(directive) = hash.get(&Yaml::String(String::from(MERGE_KEY))) {
+            let strategy = Strategy::parse(directive)?;
+            let value = match hash.get(&Yaml::String(String::from(VALUE_KEY))) {
+                Some(value) => value,
+                None => value,
+            };
+            return Ok((strategy, value));
+        }
+    }
+    Ok((Strategy::Merge, value))
+}
+
+// Merge config layers in given format, later layers win
+pub fn merge_layers(format: Format, layers: &[String]) -> Result<String> {
+    // Nothing to merge, so file is kept as written, unless it has merge directives to resolve
+    if let ([layer], false) = (layers, format == Format::Yaml) {
+        if !layer.contains(MERGE_KEY) {
+            return Ok(layer.clone());
+        }
+    }
+
+    match format {
+        Format::Yaml => {
+            let mut merged = Yaml::Null;
+            for layer in layers {
+                let mut parsed = YamlLoader::load_from_str(layer)?;
+                if parsed.len() > 1 {
+                    bail!("yaml has more than 1 docs");
+                }
+                if let Some(parsed) = parsed.pop() {
+                    merged = merge_yamls(&merged, &parsed)?;
                 }
             }
+
+            emit_yaml(&merged, layers)
         }
-        c.insert(b_k.clone(), b_v.clone());
+        Format::Json => {
+            let mut merged = Yaml::Null;
+            for layer in layers {
+                let parsed: serde_json::Value = serde_json::from_str(layer)?;
+                merged = merge_yamls(&merged, &json_to_yaml(parsed))?;
+            }
+            // ops.json, whitelist.json and others are lists of entries which are appended
+            let merged = match merged {
+                Yaml::Array(entries) => Yaml::Array(dedup(entries)),
+                merged => merged,
+            };
+            Ok(serde_json::to_string_pretty(&yaml_to_json(merged))? + "\n")
+        }
+        Format::Toml => {
+            let mut merged = Yaml::Null;
+            for layer in layers {
+                let parsed: toml::Value = toml::from_str(layer)?;
+                merged = merge_yamls(&merged, &toml_to_yaml(parsed))?;
+            }
+            match yaml_to_toml(merged) {
+                Some(toml::Value::Table(merged)) => apply_toml(&layers[0], merged),
+                _ => Err(anyhow!("merged toml is not a table")),
+            }
+        }
+        Format::Properties => {
+            let mut merged = Properties::parse(layers.first().map_or("", |l| l.as_str()));
+            for layer in layers.iter().skip(1) {
+                merged.merge(Properties::parse(layer));
+            }
+            Ok(merged.to_string())
+        }
+        // HOCON merges duplicate objects itself, so concatenation is enough
+        Format::Hocon => Ok(layers
+            .iter()
+            .map(|layer| strip_root_braces(layer))
+            .collect::<Vec<String>>()
+            .join("\n")),
+    }
+}
+
+// Write merged values into first layer, so its comments and formatting are kept
+fn apply_toml(base: &str, merged: toml::value::Table) -> Result<String> {
+    let mut document: toml_edit::Document = base.parse()?;
+    apply_toml_table(document.as_table_mut(), merged)?;
+    Ok(document.to_string())
+}
+
+fn apply_toml_table(table: &mut toml_edit::Table, merged: toml::value::Table) -> Result<()> {
+    let removed: Vec<String> = table
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !merged.contains_key(key))
+        .collect();
+    for key in removed {
+        table.remove(&key);
+    }
+
+    for (key, value) in merged {
+        match (table.get_mut(&key), value) {
+            (Some(toml_edit::Item::Table(table)), toml::Value::Table(merged)) => {
+                apply_toml_table(table, merged)?
+            }
+            (Some(item), value) if toml_item_value(&key, item).as_ref() == Some(&value) => {}
+            (_, value) => {
+                table.insert(&key, toml_item(&key, value)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Value as written in TOML document
+fn toml_item_value(key: &str, item: &toml_edit::Item) -> Option<toml::Value> {
+    let mut document = toml_edit::Document::new();
+    document.insert(key, item.clone());
+    let mut table: toml::value::Table = toml::from_str(&document.to_string()).ok()?;
+    table.remove(key)
+}
+
+fn toml_item(key: &str, value: toml::Value) -> Result<toml_edit::Item> {
+    let mut wrapper = toml::value::Table::new();
+    wrapper.insert(key.to_string(), value);
+    let mut document: toml_edit::Document = toml::to_string(&wrapper)?.parse()?;
+    Ok(document.remove(key).unwrap_or_default())
+}
+
+// Remove braces around HOCON root object, so files can be concatenated
+fn strip_root_braces(data: &str) -> String {
+    let mut start = 0;
+    for line in data.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
+            start += line.len();
+        } else {
+            break;
+        }
+    }
+
+    let body = data[start..].trim();
+    if body.starts_with('{') && body.ends_with('}') {
+        format!("{}{}\n", &data[..start], &body[1..body.len() - 1])
+    } else {
+        data.to_string()
+    }
+}
+
+fn key_to_string(key: Yaml) -> String {
+    match key {
+        Yaml::String(s) | Yaml::Real(s) => s,
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        _ => String::from("~"),
+    }
+}
+
+fn json_to_yaml(value: serde_json::Value) -> Yaml {
+    match value {
+        serde_json::Value::Null => Yaml::Null,
+        serde_json::Value::Bool(b) => Yaml::Boolean(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Yaml::Integer(i),
+            None => Yaml::Real(n.to_string()),
+        },
+        serde_json::Value::String(s) => Yaml::String(s),
+        serde_json::Value::Array(v) => Yaml::Array(v.into_iter().map(json_to_yaml).collect()),
+        serde_json::Value::Object(m) => Yaml::Hash(
+            m.into_iter()
+                .map(|(k, v)| (Yaml::String(k), json_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn yaml_to_json(value: Yaml) -> serde_json::Value {
+    match value {
+        Yaml::Boolean(b) => serde_json::Value::Bool(b),
+        Yaml::Integer(i) => serde_json::Value::from(i),
+        Yaml::Real(s) => match serde_json::from_str::<serde_json::Number>(&s) {
+            Ok(n) => serde_json::Value::Number(n),
+            Err(_) => serde_json::Value::String(s),
+        },
+        Yaml::String(s) => serde_json::Value::String(s),
+        Yaml::Array(v) => serde_json::Value::Array(v.into_iter().map(yaml_to_json).collect()),
+        Yaml::Hash(h) => serde_json::Value::Object(
+            h.into_iter()
+                .map(|(k, v)| (key_to_string(k), yaml_to_json(v)))
+                .collect(),
+        ),
+        _ => serde_json::Value::Null,
+    }
+}
+
+// TOML datetimes become strings
+fn toml_to_yaml(value: toml::Value) -> Yaml {
+    match value {
+        toml::Value::String(s) => Yaml::String(s),
+        toml::Value::Integer(i) => Yaml::Integer(i),
+        toml::Value::Float(f) => Yaml::Real(format!("{f:?}")),
+        toml::Value::Boolean(b) => Yaml::Boolean(b),
+        toml::Value::Datetime(d) => Yaml::String(d.to_string()),
+        toml::Value::Array(v) => Yaml::Array(v.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(t) => Yaml::Hash(
+            t.into_iter()
+                .map(|(k, v)| (Yaml::String(k), toml_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+// TOML has no null, so such values are dropped
+fn yaml_to_toml(value: Yaml) -> Option<toml::Value> {
+    let value = match value {
+        Yaml::Boolean(b) => toml::Value::Boolean(b),
+        Yaml::Integer(i) => toml::Value::Integer(i),
+        Yaml::Real(s) => match s.parse::<f64>() {
+            Ok(f) => toml::Value::Float(f),
+            Err(_) => toml::Value::String(s),
+        },
+        Yaml::String(s) => toml::Value::String(s),
+        Yaml::Array(v) => toml::Value::Array(v.into_iter().filter_map(yaml_to_toml).collect()),
+        Yaml::Hash(h) => toml::Value::Table(
+            h.into_iter()
+                .filter_map(|(k, v)| Some((key_to_string(k), yaml_to_toml(v)?)))
+                .collect(),
+        ),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(format: Format, layers: &[&str]) -> String {
+        let layers: Vec<String> = layers.iter().map(|layer| layer.to_string()).collect();
+        merge_layers(format, &layers).unwrap()
+    }
+
+    #[test]
+    fn json_lists_have_no_duplicates() {
+        let merged = merge(
+            Format::Json,
+            &[r#"[{"name": "A"}]"#, r#"[{"name": "A"}, {"name": "B"}]"#],
+        );
+        let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(merged, serde_json::json!([{"name": "A"}, {"name": "B"}]));
+    }
+
+    #[test]
+    fn nested_lists_are_appended() {
+        let merged = merge(
+            Format::Json,
+            &[r#"{"a": [1, 1], "b": [[1]]}"#, r#"{"a": [1], "b": [[1]]}"#],
+        );
+        let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(merged, serde_json::json!({"a": [1, 1, 1], "b": [[1], [1]]}));
+
+        let merged = merge(Format::Toml, &["a = [1]\n", "a = [1, 2]\n"]);
+        assert_eq!(merged, "a = [1, 1, 2]\n");
+
+        let merged = merge(
+            Format::Toml,
+            &[
+                "a = [1]\n",
+                "a = { __merge = \"unique\", __value = [1, 2] }\n",
+            ],
+        );
+        assert_eq!(merged, "a = [1, 2]\n");
+    }
+
+    #[test]
+    fn single_layer_is_kept_as_is() {
+        let toml = "# comment\nbind = \"0.0.0.0:25577\"  # inline\n";
+        assert_eq!(merge(Format::Toml, &[toml]), toml);
+        let json = "{\"a\":1}";
+        assert_eq!(merge(Format::Json, &[json]), json);
+    }
+
+    #[test]
+    fn toml_keeps_comments_of_first_layer() {
+        let merged = merge(
+            Format::Toml,
+            &[
+                "# Velocity config\nbind = \"0.0.0.0:25577\" # address\nmotd = \"A\"\n\n[servers]\n# backends\nlobby = \"127.0.0.1:30066\"\ntry = [\"lobby\"]\n",
+                "motd = \"B\"\n[servers]\nsurvival = \"127.0.0.1:30067\"\ntry = { __merge = \"replace\", __value = [\"survival\"] }\n",
+            ],
+        );
+        assert!(merged.starts_with("# Velocity config\nbind = \"0.0.0.0:25577\" # address\n"));
+        assert!(merged.contains("# backends\n"));
+        let merged: toml::Value = toml::from_str(&merged).unwrap();
+        assert_eq!(merged["motd"].as_str(), Some("B"));
+        assert_eq!(merged["servers"]["lobby"].as_str(), Some("127.0.0.1:30066"));
+        assert_eq!(
+            merged["servers"]["survival"].as_str(),
+            Some("127.0.0.1:30067")
+        );
+        assert_eq!(
+            merged["servers"]["try"],
+            toml::Value::Array(vec![toml::Value::String(String::from("survival"))])
+        );
+    }
+
+    #[test]
+    fn toml_deletes_keys() {
+        let merged = merge(
+            Format::Toml,
+            &["a = 1\nb = 2\n", "b = { __merge = \"delete\" }\n"],
+        );
+        assert_eq!(merged, "a = 1\n");
+    }
+
+    #[test]
+    fn properties_are_merged_by_key() {
+        let merged = merge(
+            Format::Properties,
+            &["# comment\nmotd=A\npvp=true\n", "motd=B\nport=1\n"],
+        );
+        assert_eq!(merged, "# comment\nmotd=B\npvp=true\nport=1\n");
+    }
+
+    #[test]
+    fn hocon_layers_are_overlaid() {
+        let merged = merge(Format::Hocon, &["{\n  a = 1\n}\n", "a = 2\n"]);
+        assert_eq!(merged, "\n  a = 1\n\n\na = 2\n");
     }
-    c
 }
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use crate::cache::DEFAULT_CACHE_DIR;
+use crate::include::Include;
+
+// Paths of project, so it can be used from any directory
+pub struct Project {
+    // Directory which paths from command line are relative to
+    pub base: PathBuf,
+    // Directory of config, which holds server files
+    pub root: PathBuf,
+    pub config: PathBuf,
+    pub lock: PathBuf,
+    // Default cache directory
+    pub cache: String,
+    // Name of workspace member
+    pub member: Option<String>,
+    // Includes of workspace, relative to `shared_dir`
+    pub shared_includes: Vec<Include>,
+    pub shared_dir: PathBuf,
+}
+
+impl Project {
+    // Like `git -C`, paths are relative to `directory` if it's specified.
+    // Lock is next to config by default.
+    pub fn new(directory: Option<&str>, config: Option<&str>, lock: Option<&str>) -> Project {
+        let base = PathBuf::from(directory.unwrap_or("./"));
+
+        let config = base.join(config.unwrap_or("mcstarter.yml"));
+        let root = match config.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("./"),
+        };
+        let lock = match lock {
+            Some(lock) => base.join(lock),
+            None => root.join("mcstarter.lock"),
+        };
+
+        Project {
+            cache: root.join(DEFAULT_CACHE_DIR).display().to_string(),
+            member: None,
+            shared_includes: Vec::new(),
+            shared_dir: root.clone(),
+            base,
+            root,
+            config,
+            lock,
+        }
+    }
+
+    // Path from command line, or default one
+    pub fn arg_path(&self, arg: &Option<String>, default: &str) -> String {
+        match arg {
+            Some(arg) => self.base.join(arg).display().to_string(),
+            None => default.to_string(),
+        }
+    }
+
+    // Path relative to project root
+    pub fn path(&self, path: &str) -> String {
+        self.root.join(path).display().to_string()
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Line of .properties file
+enum Line {
+    // Comment, blank line or anything else kept as is
+    Other(String),
+    // Entry with raw (still escaped) value
+    Entry { key: String, raw: String },
+}
+
+// .properties file which keeps comments and entries order
+pub struct Properties {
+    lines: Vec<Line>,
+    index: HashMap<String, usize>,
+}
+
+impl Properties {
+    pub fn parse(data: &str) -> Properties {
+        let mut properties = Properties {
+            lines: Vec::new(),
+            index: HashMap::new(),
+        };
+
+        let mut logical = String::new();
+        for line in data.lines() {
+            if logical.is_empty() {
+                let trimmed = line.trim_start();
+                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('!') {
+                    properties.lines.push(Line::Other(line.to_string()));
+                    continue;
+                }
+                logical.push_str(trimmed);
+            } else {
+                logical.push_str(line.trim_start());
+            }
+
+            // Odd number of trailing backslashes continues line
+            if ends_with_continuation(&logical) {
+                logical.pop();
+                continue;
+            }
+
+            let (key, raw) = split_entry(&logical);
+            properties.set_raw(&key, raw);
+            logical.clear();
+        }
+        if !logical.is_empty() {
+            let (key, raw) = split_entry(&logical);
+            properties.set_raw(&key, raw);
+        }
+
+        properties
+    }
+
+    // Value of key, unescaped like java.util.Properties does
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.lines.get(*self.index.get(key)?)? {
+            Line::Entry { raw, .. } => Some(unescape(raw)),
+            Line::Other(_) => None,
+        }
+    }
+
+    // Set value, escaping it like java.util.Properties does
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.set_raw(key, escape(value));
+    }
+
+    // Set raw value, keeping position of existing key
+    pub fn set_raw(&mut self, key: &str, raw: String) {
+        match self.index.get(key) {
+            Some(&i) => {
+                self.lines[i] = Line::Entry {
+                    key: key.to_string(),
+                    raw,
+                }
+            }
+            None => {
+                self.index.insert(key.to_string(), self.lines.len());
+                self.lines.push(Line::Entry {
+                    key: key.to_string(),
+                    raw,
+                });
+            }
+        }
+    }
+
+    // Overlay entries of other file over this one
+    pub fn merge(&mut self, other: Properties) {
+        for line in other.lines {
+            if let Line::Entry { key, raw } = line {
+                self.set_raw(&key, raw);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Properties {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Other(line) => writeln!(f, "{line}")?,
+                Line::Entry { key, raw } => writeln!(f, "{key}={raw}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '=' | ':' | '#' | '!' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // Leading space would be trimmed on load
+            ' ' if i == 0 => escaped.push_str("\\ "),
+            // Older servers read properties as ISO 8859-1
+            c if !c.is_ascii() || c.is_ascii_control() => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    escaped.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(raw: &str) -> String {
+    // `\uXXXX` are UTF-16 units, so surrogate pairs are joined at the end
+    let mut units: Vec<u16> = Vec::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('f') => '\u{c}',
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    match u16::from_str_radix(&hex, 16) {
+                        Ok(unit) => units.push(unit),
+                        Err(_) => units.extend(hex.encode_utf16()),
+                    }
+                    continue;
+                }
+                Some(c) => c,
+                None => continue,
+            },
+            c => c,
+        };
+        units.extend(c.encode_utf16(&mut [0; 2]).iter());
+    }
+    String::from_utf16_lossy(&units)
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+// Split logical line into raw key and raw value
+fn split_entry(line: &str) -> (String, String) {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '=' | ':' | ' ' | '\t' => {
+                let key = line[..i].to_string();
+                let mut rest = line[i..].trim_start_matches([' ', '\t']);
+                if c == ' ' || c == '\t' {
+                    rest = rest.strip_prefix(['=', ':']).unwrap_or(rest);
+                } else {
+                    rest = &rest[1..];
+                }
+                return (key, rest.trim_start_matches([' ', '\t']).to_string());
+            }
+            _ => {}
+        }
+    }
+    (line.to_string(), String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_comments_and_order() {
+        let mut properties = Properties::parse("# comment\nb=1\na=2\n");
+        properties.set("a", "3");
+        properties.set("c", "4");
+        assert_eq!(properties.to_string(), "# comment\nb=1\na=3\nc=4\n");
+    }
+
+    #[test]
+    fn gets_unescaped_value() {
+        let properties = Properties::parse("level-name = my\\ world\\u00e9\nmotd=a\\\n  b\n");
+        assert_eq!(
+            properties.get("level-name").as_deref(),
+            Some("my world\u{e9}")
+        );
+        assert_eq!(properties.get("motd").as_deref(), Some("ab"));
+        assert_eq!(properties.get("missing"), None);
+    }
+
+    #[test]
+    fn round_trips_escaped_value() {
+        let mut properties = Properties::parse("");
+        properties.set("motd", " a=b: \u{1F600}\n");
+        let properties = Properties::parse(&properties.to_string());
+        assert_eq!(properties.get("motd").as_deref(), Some(" a=b: \u{1F600}\n"));
+    }
+}
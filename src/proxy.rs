@@ -0,0 +1,297 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::build::Rendered;
+use crate::config::{Core, Proxy, ProxyRole, ProxyServer, ProxySoftware};
+use crate::java::minecraft_version;
+use crate::merger::Format;
+use crate::project::Project;
+use crate::server::set_properties;
+
+const SECRET_FILE: &str = "forwarding.secret";
+
+// Write forwarding settings into config of proxy or backend
+pub fn render_proxy(
+    project: &Project,
+    proxy: &Proxy,
+    core: &Core,
+    rendered: &mut Rendered,
+) -> Result<()> {
+    match (proxy.role, proxy.software) {
+        (ProxyRole::Proxy, ProxySoftware::Velocity) => {
+            let secret = read_secret(project, proxy)?;
+            rendered.put(SECRET_FILE, format!("{secret}\n"));
+            let mut velocity = json!({
+                "online-mode": proxy.online_mode,
+                "player-info-forwarding-mode": "modern",
+                "forwarding-secret-file": SECRET_FILE,
+            });
+            if !proxy.servers.is_empty() {
+                velocity["servers"] = servers_table(proxy)?;
+                velocity["forced-hosts"] = forced_hosts_table(&proxy.servers);
+            }
+            rendered.merge("velocity.toml", Format::Toml, toml::to_string(&velocity)?)?;
+        }
+        (ProxyRole::Proxy, ProxySoftware::Bungeecord) => {
+            let mut bungeecord = json!({
+                "online_mode": proxy.online_mode,
+                "ip_forward": true,
+            });
+            if !proxy.servers.is_empty() {
+                bungeecord["servers"] = bungeecord_servers(proxy)?;
+            }
+            rendered.merge(
+                "config.yml",
+                Format::Yaml,
+                serde_yaml::to_string(&bungeecord)?,
+            )?;
+        }
+        (ProxyRole::Backend, software) => {
+            if !proxy.servers.is_empty() || proxy.try_servers.is_some() {
+                bail!("proxy.servers and proxy.try can be set only for proxy role");
+            }
+            match software {
+                ProxySoftware::Velocity => render_velocity_backend(project, proxy, core, rendered)?,
+                ProxySoftware::Bungeecord => rendered.merge(
+                    "spigot.yml",
+                    Format::Yaml,
+                    serde_yaml::to_string(&json!({"settings": {"bungeecord": true}}))?,
+                )?,
+            }
+
+            // Players are authenticated by proxy
+            set_properties(
+                rendered,
+                &[(String::from("online-mode"), String::from("false"))],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn render_velocity_backend(
+    project: &Project,
+    proxy: &Proxy,
+    core: &Core,
+    rendered: &mut Rendered,
+) -> Result<()> {
+    let velocity = json!({
+        "enabled": true,
+        "online-mode": proxy.online_mode,
+        "secret": read_secret(project, proxy)?,
+    });
+    // Paper moved its config to config/paper-global.yml in 1.19
+    let (key, paper) = match minecraft_version(&core.version) {
+        Some((minor, _)) if minor < 19 => (
+            "paper.yml",
+            json!({"settings": {"velocity-support": velocity}}),
+        ),
+        _ => (
+            "config/paper-global.yml",
+            json!({"proxies": {"velocity": velocity}}),
+        ),
+    };
+    rendered.merge(key, Format::Yaml, serde_yaml::to_string(&paper)?)
+}
+
+// `servers` of BungeeCord config.yml, it has try and forced hosts in listeners
+fn bungeecord_servers(proxy: &Proxy) -> Result<Value> {
+    if proxy.try_servers.is_some() || proxy.servers.iter().any(|s| !s.forced_hosts.is_empty()) {
+        bail!("proxy.try and forced_hosts are supported only for Velocity, set listeners in config.yml");
+    }
+
+    let mut servers = Map::new();
+    for server in &proxy.servers {
+        let entry = json!({
+            "address": format!("{}:{}", server.host, server.port),
+            "restricted": false,
+        });
+        if servers.insert(server.name.clone(), entry).is_some() {
+            bail!("proxy server {} is declared twice", server.name);
+        }
+    }
+    Ok(Value::Object(servers))
+}
+
+// `[servers]` of velocity.toml, servers from includes are kept
+fn servers_table(proxy: &Proxy) -> Result<Value> {
+    let mut servers = Map::new();
+    for server in &proxy.servers {
+        let address = format!("{}:{}", server.host, server.port);
+        if servers
+            .insert(server.name.clone(), json!(address))
+            .is_some()
+        {
+            bail!("proxy server {} is declared twice", server.name);
+        }
+    }
+
+    let try_servers: Vec<String> = match &proxy.try_servers {
+        Some(try_servers) => {
+            for name in try_servers {
+                if !servers.contains_key(name) {
+                    bail!("server {name} from proxy.try is not in proxy.servers");
+                }
+            }
+            try_servers.clone()
+        }
+        None => proxy.servers.iter().map(|s| s.name.clone()).collect(),
+    };
+    // Lists are appended on merge, but try list must be exactly as declared
+    servers.insert(
+        String::from("try"),
+        json!({"__merge": "replace", "__value": try_servers}),
+    );
+    Ok(Value::Object(servers))
+}
+
+// `[forced-hosts]` of velocity.toml, host to servers in order of declaration
+fn forced_hosts_table(servers: &[ProxyServer]) -> Value {
+    let mut hosts: Map<String, Value> = Map::new();
+    for server in servers {
+        for host in &server.forced_hosts {
+            let names = hosts
+                .entry(host.clone())
+                .or_insert_with(|| json!({"__merge": "replace", "__value": []}));
+            if let Some(Value::Array(names)) = names.get_mut("__value") {
+                names.push(json!(server.name));
+            }
+        }
+    }
+    Value::Object(hosts)
+}
+
+// Secret file is shared by all members of workspace by default
+pub fn secret_path(project: &Project, proxy: &Proxy) -> PathBuf {
+    match &proxy.secret_file {
+        Some(file) => project.root.join(file),
+        None => project.shared_dir.join(SECRET_FILE),
+    }
+}
+
+fn read_secret(project: &Project, proxy: &Proxy) -> Result<String> {
+    if let Some(secret) = &proxy.secret {
+        return check_secret(secret.clone());
+    }
+
+    let path = secret_path(project, proxy);
+    match fs::read_to_string(&path) {
+        Ok(secret) => check_secret(secret.trim().to_string()),
+        Err(_) => bail!(
+            "forwarding secret {} not found, run mcstarter build or lock to generate it",
+            path.display()
+        ),
+    }
+}
+
+// Generate secret file if it's missing, only lock and build do it
+pub fn ensure_secret(project: &Project, proxy: &Proxy) -> Result<()> {
+    if proxy.software != ProxySoftware::Velocity || proxy.secret.is_some() {
+        return Ok(());
+    }
+    let path = secret_path(project, proxy);
+    if path.exists() {
+        return Ok(());
+    }
+
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let secret = hex::encode(bytes);
+
+    fs::write(&path, format!("{secret}\n"))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    println!("Generated forwarding secret in {}", path.display());
+    ignore_secret(&path)
+}
+
+// Secret must not be committed, so it's added to .gitignore next to it
+fn ignore_secret(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Ok(()),
+    };
+
+    let gitignore = dir.join(".gitignore");
+    let mut data = fs::read_to_string(&gitignore).unwrap_or_default();
+    let entry = format!("/{name}");
+    if data
+        .lines()
+        .any(|line| line.trim() == entry || line.trim() == name)
+    {
+        return Ok(());
+    }
+    if !data.is_empty() && !data.ends_with('\n') {
+        data.push('\n');
+    }
+    data.push_str(&entry);
+    data.push('\n');
+    fs::write(&gitignore, data)?;
+    println!("Added {entry} to {}", gitignore.display());
+    Ok(())
+}
+
+fn check_secret(secret: String) -> Result<String> {
+    if secret.is_empty() {
+        bail!("forwarding secret is empty");
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn velocity_toml_without_base_file() {
+        let proxy: Proxy = serde_yaml::from_str(
+            r#"
+role: proxy
+secret: test-secret
+servers:
+  - { name: lobby, port: 30001, forced_hosts: [lobby.example.com] }
+  - { name: survival, port: 30002, forced_hosts: [lobby.example.com] }
+try: [survival, lobby]
+"#,
+        )
+        .unwrap();
+        let core = Core {
+            name: String::from("velocity"),
+            version: String::from("3.1"),
+            source: None,
+            url: None,
+        };
+        let project = Project::new(None, None, None);
+        let mut rendered = Rendered {
+            files: Vec::new(),
+            copies: Vec::new(),
+        };
+        render_proxy(&project, &proxy, &core, &mut rendered).unwrap();
+
+        let velocity = rendered.take("velocity.toml").unwrap().unwrap();
+        assert!(!velocity.contains("__"), "{velocity}");
+        let velocity: toml::Value = toml::from_str(&velocity).unwrap();
+        assert_eq!(
+            velocity["servers"]["try"].as_array().unwrap(),
+            &vec![toml::Value::from("survival"), toml::Value::from("lobby")]
+        );
+        assert_eq!(
+            velocity["servers"]["lobby"].as_str(),
+            Some("127.0.0.1:30001")
+        );
+        assert_eq!(
+            velocity["forced-hosts"]["lobby.example.com"]
+                .as_array()
+                .unwrap(),
+            &vec![toml::Value::from("lobby"), toml::Value::from("survival")]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, Context, Result};
+use serde_yaml::Value;
+use sha1::{Digest, Sha1};
+
+use std::collections::HashMap;
+use std::fs;
+
+use crate::build::Rendered;
+use crate::config::{ResourcePack, Server, ServerProperties};
+use crate::lock::{get_lock_entry, resource_pack_key};
+use crate::properties::Properties;
+
+pub const EULA_URL: &str = "https://aka.ms/MinecraftEULA";
+
+// Layer server section over eula.txt and server.properties from includes
+pub fn render_server(server: &Server, rendered: &mut Rendered) -> Result<()> {
+    if server.eula {
+        rendered.put(
+            "eula.txt",
+            format!("# Accepted with server.eula in mcstarter.yml, see {EULA_URL}\neula=true\n"),
+        );
+    }
+
+    let entries = property_entries(&server.properties)?;
+    if !entries.is_empty() {
+        set_properties(rendered, &entries)?;
+    }
+    Ok(())
+}
+
+// Resource pack from cache, so its sha1 is known before server starts
+pub fn render_resource_pack(
+    resource_pack: &ResourcePack,
+    lock: &HashMap<String, String>,
+    cache: &str,
+    rendered: &mut Rendered,
+) -> Result<()> {
+    let hash = get_lock_entry(&resource_pack_key(&resource_pack.url), lock)?;
+    let pack = fs::read(format!("{cache}/{hash}"))
+        .context("resource pack is not downloaded, run mcstarter download")?;
+
+    let mut entries = vec![
+        (String::from("resource-pack"), resource_pack.url.clone()),
+        (
+            String::from("resource-pack-sha1"),
+            hex::encode(Sha1::digest(pack)),
+        ),
+    ];
+    if let Some(prompt) = &resource_pack.prompt {
+        entries.push((String::from("resource-pack-prompt"), prompt.clone()));
+    }
+    if let Some(required) = resource_pack.required {
+        entries.push((String::from("require-resource-pack"), required.to_string()));
+    }
+    set_properties(rendered, &entries)
+}
+
+// Set entries over server.properties, creating it if there's none
+pub fn set_properties(rendered: &mut Rendered, entries: &[(String, String)]) -> Result<()> {
+    let data = rendered.take("server.properties")?.unwrap_or_default();
+    let mut properties = Properties::parse(&data);
+    for (key, value) in entries {
+        properties.set(key, value);
+    }
+    rendered.put("server.properties", properties.to_string());
+    Ok(())
+}
+
+// Keys which are set, in order of ServerProperties
+fn property_entries(properties: &ServerProperties) -> Result<Vec<(String, String)>> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    if let Value::Mapping(mapping) = serde_yaml::to_value(properties)? {
+        for (key, value) in mapping {
+            let key = match key {
+                Value::String(key) => key,
+                key => bail!("invalid server.properties key {key:?}"),
+            };
+            let value = match value {
+                Value::Null => continue,
+                Value::Bool(value) => value.to_string(),
+                Value::Number(value) => value.to_string(),
+                Value::String(value) => value,
+                _ => bail!("server.properties key {key} must be a string, number or bool"),
+            };
+            entries.push((key, value));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_config;
+    use crate::env::Vars;
+    use crate::project::Project;
+
+    use std::env;
+
+    #[tokio::test]
+    async fn renders_templated_port() {
+        let dir = env::temp_dir().join(format!("mcstarter-port-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".env"), "TEST_PORT=25570\n").unwrap();
+        fs::write(
+            dir.join("mcstarter.yml"),
+            r#"core: { name: paper, version: "1.19" }
+launch: {}
+server:
+  properties:
+    server-port: ${TEST_PORT}
+    online-mode: "${TEST_ONLINE:-false}"
+"#,
+        )
+        .unwrap();
+        let project = Project::new(dir.to_str(), None, None);
+        let vars = Vars::load(&project.root, None).unwrap();
+
+        let config = load_config(&project, true, &vars, None, &project.cache)
+            .await
+            .unwrap();
+        assert_eq!(config.server.properties.server_port, Some(25570));
+        assert_eq!(config.server.properties.online_mode, Some(false));
+
+        let mut rendered = Rendered {
+            files: Vec::new(),
+            copies: Vec::new(),
+        };
+        render_server(&config.server, &mut rendered).unwrap();
+        let properties = rendered.take("server.properties").unwrap().unwrap();
+        assert!(properties.contains("server-port=25570\n"));
+        assert!(properties.contains("online-mode=false\n"));
+
+        // Lock loads config without variables
+        let config = load_config(&project, false, &vars, None, &project.cache)
+            .await
+            .unwrap();
+        assert_eq!(config.server.properties.server_port, None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
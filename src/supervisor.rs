@@ -0,0 +1,407 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, TimeZone};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::ChildStdin;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::sleep;
+
+use std::collections::{LinkedList, VecDeque};
+use std::fs;
+use std::future::Future;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::config::{Launch, ScheduledTask, Shutdown};
+use crate::cron::Schedule;
+use crate::launch::LaunchCommand;
+
+// Console socket in server directory
+pub const SOCKET_FILE: &str = ".mcstarter.sock";
+
+// SIGTERM and SIGINT, both stop server gracefully
+struct StopSignals {
+    term: Signal,
+    int: Signal,
+}
+
+impl StopSignals {
+    fn new() -> Result<StopSignals> {
+        Ok(StopSignals {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.term.recv() => {}
+            _ = self.int.recv() => {}
+        }
+    }
+}
+
+// Run server in current directory until it's stopped, restarting it on crashes.
+// Returns exit code.
+pub async fn supervise(
+    launch_command: &LaunchCommand,
+    launch: &Launch,
+    schedule: &LinkedList<ScheduledTask>,
+) -> Result<i32> {
+    let tasks = parse_schedule(schedule)?;
+
+    if Path::new(SOCKET_FILE).exists() {
+        fs::remove_file(SOCKET_FILE)?;
+    }
+    // Anyone who can write to socket controls server, so it's created accessible only by owner
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(SOCKET_FILE);
+    unsafe { libc::umask(umask) };
+    let listener = listener.context("can't create console socket")?;
+
+    let result = run(launch_command, launch, tasks, listener).await;
+    fs::remove_file(SOCKET_FILE)?;
+    result
+}
+
+async fn run(
+    launch_command: &LaunchCommand,
+    launch: &Launch,
+    tasks: Vec<(Schedule, Action)>,
+    listener: UnixListener,
+) -> Result<i32> {
+    let settings = &launch.supervisor;
+    let mut signals = StopSignals::new()?;
+    let mut crashes: VecDeque<Instant> = VecDeque::new();
+    let window = Duration::from_secs(settings.window);
+
+    // Console commands from terminal and socket clients
+    let (commands_tx, mut commands) = mpsc::channel::<String>(64);
+    // Server output for socket clients
+    let (output, _) = broadcast::channel::<String>(256);
+
+    // Scheduled restarts, ignored if server is not running
+    let (restarts_tx, mut restarts) = mpsc::channel::<()>(1);
+
+    tokio::spawn(read_terminal(commands_tx.clone()));
+    tokio::spawn(run_schedule(tasks, commands_tx.clone(), restarts_tx));
+    tokio::spawn(accept_clients(listener, commands_tx, output.clone()));
+
+    loop {
+        let mut command = Command::new(&launch_command.java);
+        // Own process group, so Ctrl+C in terminal reaches only supervisor
+        command
+            .args(&launch_command.args)
+            .envs(&launch_command.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        let mut child = tokio::process::Command::from(command)
+            .spawn()
+            .map_err(|err| anyhow!("can't launch: {err}"))?;
+        let (stdin, stdin_rx) = mpsc::channel::<String>(64);
+        tokio::spawn(write_input(child.stdin.take().unwrap(), stdin_rx));
+        let stdout_task = tokio::spawn(print_output(
+            child.stdout.take().unwrap(),
+            output.clone(),
+            false,
+        ));
+        let stderr_task = tokio::spawn(print_output(
+            child.stderr.take().unwrap(),
+            output.clone(),
+            true,
+        ));
+
+        // Resolves if server isn't stopped in time
+        let mut shutdown: Option<Pin<Box<dyn Future<Output = ()> + '_>>> = None;
+        let mut killed = false;
+        let mut restarting = false;
+        let status = loop {
+            tokio::select! {
+                // Commands scheduled together with restart go before it
+                biased;
+                status = child.wait() => break status?,
+                Some(command) = commands.recv() => send_command(&stdin, command).await,
+                _ = signals.recv(), if shutdown.is_none() || restarting => {
+                    println!("Stopping server...");
+                    // Restart which is in progress becomes stop
+                    if !restarting {
+                        shutdown = Some(Box::pin(shutdown_sequence(stdin.clone(), &launch.shutdown)));
+                    }
+                    restarting = false;
+                }
+                Some(()) = restarts.recv(), if shutdown.is_none() => {
+                    println!("Restarting server...");
+                    restarting = true;
+                    shutdown = Some(Box::pin(shutdown_sequence(stdin.clone(), &launch.shutdown)));
+                }
+                _ = async { shutdown.as_mut().unwrap().await }, if shutdown.is_some() && !killed => {
+                    println!("Server didn't stop in {}s, killing it", launch.shutdown.timeout);
+                    killed = true;
+                    child.start_kill()?;
+                }
+            }
+        };
+        let stopping = shutdown.is_some();
+        // Print everything server wrote before exit
+        stdout_task.await??;
+        stderr_task.await??;
+
+        if restarting {
+            continue;
+        }
+
+        let code = exit_code(status);
+        if stopping || status.success() {
+            return Ok(code);
+        }
+
+        let now = Instant::now();
+        crashes.push_back(now);
+        while crashes
+            .front()
+            .is_some_and(|crash| now.duration_since(*crash) > window)
+        {
+            crashes.pop_front();
+        }
+        if crashes.len() > settings.max_restarts {
+            println!(
+                "Server crashed {} times in {}s, giving up",
+                crashes.len(),
+                settings.window
+            );
+            return Ok(code);
+        }
+
+        // Delay doubles with every crash in window
+        let delay = settings
+            .backoff
+            .saturating_mul(1 << (crashes.len() - 1).min(16))
+            .min(settings.max_backoff);
+        println!("Server {status}, restarting in {delay}s");
+
+        let restart = sleep(Duration::from_secs(delay));
+        tokio::pin!(restart);
+        loop {
+            tokio::select! {
+                _ = &mut restart => break,
+                _ = signals.recv() => return Ok(code),
+                Some(command) = commands.recv() => {
+                    println!("Server is not running, {command} is ignored");
+                }
+                Some(()) = restarts.recv() => {}
+            }
+        }
+    }
+}
+
+// What scheduled task does
+enum Action {
+    Restart,
+    Command(String),
+}
+
+fn parse_schedule(schedule: &LinkedList<ScheduledTask>) -> Result<Vec<(Schedule, Action)>> {
+    schedule
+        .iter()
+        .map(|task| {
+            let cron = Schedule::parse(&task.cron)
+                .with_context(|| format!("invalid schedule {}", task.cron))?;
+            let action = match (task.restart, &task.command) {
+                (true, None) => Action::Restart,
+                (false, Some(command)) => Action::Command(command.clone()),
+                _ => bail!("task {} must have either restart or command", task.cron),
+            };
+            Ok((cron, action))
+        })
+        .collect()
+}
+
+// Run tasks at local time
+async fn run_schedule(
+    tasks: Vec<(Schedule, Action)>,
+    commands: mpsc::Sender<String>,
+    restarts: mpsc::Sender<()>,
+) -> Result<()> {
+    let mut after = Local::now().naive_local();
+    loop {
+        let next = match tasks
+            .iter()
+            .filter_map(|(cron, _)| cron.next_after(after))
+            .min()
+        {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+
+        // Time which is skipped by DST change never comes
+        if let Some(time) = Local.from_local_datetime(&next).earliest() {
+            let delay = (time - Local::now()).to_std().unwrap_or_default();
+            sleep(delay).await;
+
+            for (cron, action) in &tasks {
+                if cron.next_after(after) != Some(next) {
+                    continue;
+                }
+                match action {
+                    Action::Restart => {
+                        // Restart is already in progress
+                        let _ = restarts.try_send(());
+                    }
+                    Action::Command(command) => commands.send(command.clone()).await?,
+                }
+            }
+        }
+        after = next;
+    }
+}
+
+// Forward lines typed in terminal
+async fn read_terminal(commands: mpsc::Sender<String>) -> Result<()> {
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        commands.send(line).await?;
+    }
+    Ok(())
+}
+
+async fn accept_clients(
+    listener: UnixListener,
+    commands: mpsc::Sender<String>,
+    output: broadcast::Sender<String>,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_client(stream, commands.clone(), output.subscribe()));
+    }
+}
+
+// Client sends commands line by line and receives server output
+async fn serve_client(
+    stream: UnixStream,
+    commands: mpsc::Sender<String>,
+    mut output: broadcast::Receiver<String>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => commands.send(line).await?,
+                None => return Ok(()),
+            },
+            line = output.recv() => match line {
+                Ok(line) => writer.write_all(format!("{line}\n").as_bytes()).await?,
+                // Slow client misses some output
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+// Print stdout or stderr of server and relay it to socket clients
+async fn print_output<R: AsyncRead + Unpin>(
+    stream: R,
+    output: broadcast::Sender<String>,
+    is_stderr: bool,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut buf: Vec<u8> = Vec::new();
+    while reader.read_until(b'\n', &mut buf).await? > 0 {
+        // Plugins may print anything
+        let line = String::from_utf8_lossy(&buf)
+            .trim_end_matches(['\n', '\r'])
+            .to_string();
+        buf.clear();
+        if is_stderr {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+        // No clients is not an error
+        let _ = output.send(line);
+    }
+    Ok(())
+}
+
+// Warn players, save world and stop server, then wait for it
+async fn shutdown_sequence(stdin: mpsc::Sender<String>, settings: &Shutdown) {
+    let mut countdown = settings.countdown.clone();
+    countdown.sort_unstable_by(|a, b| b.cmp(a));
+    countdown.dedup();
+
+    for (i, seconds) in countdown.iter().enumerate() {
+        let message = settings.message.replace("{seconds}", &seconds.to_string());
+        send_command(&stdin, message).await;
+        let next = countdown.get(i + 1).copied().unwrap_or(0);
+        sleep(Duration::from_secs(seconds - next)).await;
+    }
+
+    for command in &settings.commands {
+        send_command(&stdin, command.clone()).await;
+    }
+    send_command(&stdin, settings.stop.clone()).await;
+
+    sleep(Duration::from_secs(settings.timeout)).await;
+}
+
+async fn send_command(stdin: &mpsc::Sender<String>, command: String) {
+    // Writer is gone only with server
+    let _ = stdin.send(command).await;
+}
+
+// Write console commands, server may be already gone
+async fn write_input(mut stdin: ChildStdin, mut commands: mpsc::Receiver<String>) {
+    while let Some(command) = commands.recv().await {
+        let line = format!("{command}\n");
+        if let Err(err) = stdin.write_all(line.as_bytes()).await {
+            println!("Can't send {command}: {err}");
+            return;
+        }
+    }
+}
+
+// Code of process, like shells do for killed ones
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+// Attach terminal to console of supervised server
+pub async fn console(target: &str) -> Result<()> {
+    let stream = connect(target).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let (mut stdin, mut stdout) = (io::stdin(), io::stdout());
+    tokio::select! {
+        result = io::copy(&mut reader, &mut stdout) => result?,
+        result = io::copy(&mut stdin, &mut writer) => result?,
+    };
+    Ok(())
+}
+
+// Send single command to supervised server
+pub async fn send(target: &str, command: &str) -> Result<()> {
+    let mut stream = connect(target).await?;
+    stream.write_all(format!("{command}\n").as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn connect(target: &str) -> Result<UnixStream> {
+    let path = Path::new(target).join(SOCKET_FILE);
+    UnixStream::connect(&path).await.with_context(|| {
+        format!(
+            "can't connect to {}, is server launched with --supervise?",
+            path.display()
+        )
+    })
+}
@@ -0,0 +1,174 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::DEFAULT_CACHE_DIR;
+use crate::config::{load_config, Plugin};
+use crate::env::Vars;
+use crate::include::Include;
+use crate::project::Project;
+
+pub const WORKSPACE_FILE: &str = "mcstarter-workspace.yml";
+
+fn default_includes() -> Vec<Include> {
+    let includes: Vec<Include> = Vec::new();
+    includes
+}
+
+fn default_cache() -> String {
+    String::from(DEFAULT_CACHE_DIR)
+}
+
+// Several servers sharing includes, lock and cache
+#[derive(Deserialize)]
+pub struct Workspace {
+    // Server directories, relative to workspace file
+    pub members: Vec<String>,
+    // Includes of every member, merged below member's own
+    #[serde(default = "default_includes")]
+    pub include: Vec<Include>,
+    #[serde(default = "default_cache")]
+    pub cache: String,
+    // Directory of workspace file
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+impl Workspace {
+    // Find workspace file in directory or its parents
+    pub fn find(dir: &Path) -> Result<Option<Workspace>> {
+        let canonical =
+            fs::canonicalize(dir).with_context(|| format!("{} not found", dir.display()))?;
+        for ancestor in canonical.ancestors() {
+            let path = ancestor.join(WORKSPACE_FILE);
+            if !path.exists() {
+                continue;
+            }
+
+            let data = fs::read_to_string(&path)?;
+            let mut workspace: Workspace = serde_yaml::from_str(&data)
+                .with_context(|| format!("can't parse {}", path.display()))?;
+            // Keep paths relative when possible
+            workspace.dir = if ancestor == canonical {
+                dir.to_path_buf()
+            } else {
+                ancestor.to_path_buf()
+            };
+            return Ok(Some(workspace));
+        }
+        Ok(None)
+    }
+
+    // Member which has project in its directory
+    fn member_of(&self, root: &Path) -> Option<String> {
+        let root = fs::canonicalize(root).ok()?;
+        self.members
+            .iter()
+            .find(|member| fs::canonicalize(self.dir.join(member)).is_ok_and(|dir| dir == root))
+            .cloned()
+    }
+
+    // Share includes, lock and cache of workspace with member
+    fn apply(&self, project: &mut Project, member: &str, keep_lock: bool) {
+        project.member = Some(member.to_string());
+        project.shared_includes = self.include.clone();
+        project.shared_dir = self.dir.clone();
+        project.cache = self.dir.join(&self.cache).display().to_string();
+        if !keep_lock {
+            project.lock = self.dir.join("mcstarter.lock");
+        }
+    }
+}
+
+// Shared plugins must have same version in all members, unless overridden.
+// `versions` holds plugin name -> (version, member) of members checked before.
+pub fn check_plugin_versions(
+    versions: &mut HashMap<String, (String, String)>,
+    member: &str,
+    plugins: &HashMap<String, Plugin>,
+) -> Result<()> {
+    for (name, plugin) in plugins {
+        if plugin.version_override {
+            continue;
+        }
+        match versions.get(name) {
+            Some((version, other)) if version != &plugin.version => bail!(
+                "plugin {name} is {version} in {other} and {} in {member}, use same version or set override: true",
+                plugin.version
+            ),
+            Some(_) => {}
+            None => {
+                versions.insert(name.clone(), (plugin.version.clone(), member.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Check plugin versions of all members, even if only some of them are selected
+pub async fn check_members_plugins(project: &Project) -> Result<()> {
+    let base = project.base.display().to_string();
+    let members = select_projects(
+        Project::new(Some(&base), None, None),
+        true,
+        &[],
+        false,
+        false,
+    )?;
+
+    let mut versions: HashMap<String, (String, String)> = HashMap::new();
+    for member in &members {
+        let vars = Vars::load(&member.root, None)?;
+        let config = load_config(member, false, &vars, None, &member.cache).await?;
+        let name = member.member.as_deref().unwrap_or_default();
+        check_plugin_versions(&mut versions, name, &config.plugins)?;
+    }
+    Ok(())
+}
+
+// Projects to run command on: selected members, or project itself.
+// Project inside of member directory still uses workspace.
+pub fn select_projects(
+    mut project: Project,
+    all: bool,
+    members: &[String],
+    custom_config: bool,
+    custom_lock: bool,
+) -> Result<Vec<Project>> {
+    let workspace = Workspace::find(&project.base)?;
+
+    if !all && members.is_empty() {
+        if let Some(workspace) = workspace {
+            if let Some(member) = workspace.member_of(&project.root) {
+                workspace.apply(&mut project, &member, custom_lock);
+            }
+        }
+        return Ok(vec![project]);
+    }
+
+    if custom_config {
+        bail!("--config can't be used with --all or --member");
+    }
+    let workspace = workspace.ok_or_else(|| anyhow!("{WORKSPACE_FILE} not found"))?;
+
+    let names = if all { &workspace.members } else { members };
+    names
+        .iter()
+        .map(|name| {
+            if !workspace.members.contains(name) {
+                bail!("member {name} not found in {WORKSPACE_FILE}");
+            }
+            let dir = workspace.dir.join(name).display().to_string();
+            let mut member = Project::new(Some(&dir), None, None);
+            member.base = project.base.clone();
+            if custom_lock {
+                member.lock = project.lock.clone();
+            }
+            workspace.apply(&mut member, name, custom_lock);
+            Ok(member)
+        })
+        .collect()
+}